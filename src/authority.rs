@@ -0,0 +1,163 @@
+use std::net::Ipv4Addr;
+
+/// A directory authority as Tor has it hardcoded.
+#[derive(Debug)]
+pub struct DirectoryAuthority {
+    pub name: String,
+    pub ip: Ipv4Addr,
+    /// The port of the directory. 0 if it has none.
+    pub dir_port: u16,
+    pub or_port: u16,
+    /// The fingerprint of the identity key of the authority's relay, as an onion router in the
+    /// consensus has it.
+    pub fingerprint: &'static str,
+    /// The fingerprint of the authority's v3 identity key, as in the "dir-source" and
+    /// "directory-signature" lines of the consensus. It differs from `fingerprint`.
+    pub v3ident: &'static str,
+}
+
+impl DirectoryAuthority {
+    /// The URL of the directory authority's directory, e.g. `http://171.25.193.9:443`.
+    /// Returns `None` if the authority has no dir port, in which case the directory can only be
+    /// accessed over the OR port (begindir).
+    pub fn base_url(&self) -> Option<String> {
+        if self.dir_port == 0 {
+            return None;
+        }
+
+        // TODO: https://github.com/servo/rust-url
+        Some(format!("http://{}:{}", self.ip, self.dir_port))
+    }
+}
+
+/// The v3 directory authorities.
+// https://consensus-health.torproject.org/
+// https://gitlab.torproject.org/tpo/core/tor/-/blob/main/src/app/config/auth_dirs.inc
+pub fn directory_authorities() -> Vec<DirectoryAuthority> {
+    vec![
+        DirectoryAuthority {
+            name: "moria1".into(),
+            ip: Ipv4Addr::new(128, 31, 0, 39),
+            dir_port: 9231,
+            or_port: 9201,
+            fingerprint: "1A25C6358DB91342AA51720A5038B72742732498",
+            v3ident: "F533C81CEF0BC0267857C99B2F471ADF249FA232",
+        },
+        DirectoryAuthority {
+            name: "tor26".into(),
+            ip: Ipv4Addr::new(217, 196, 147, 77),
+            dir_port: 80,
+            or_port: 443,
+            fingerprint: "FAA4BCA4A6AC0CB4CBEE6BCA1D4D5FE34BF621CD",
+            v3ident: "2F3DF9CA0E5D36F2685A2DA67184EB8DCB8CBA8C",
+        },
+        DirectoryAuthority {
+            name: "dizum".into(),
+            ip: Ipv4Addr::new(45, 66, 35, 11),
+            dir_port: 80,
+            or_port: 443,
+            fingerprint: "7EA6EAD6FD83083C538F44038BBFA077587DD755",
+            v3ident: "E8A9C45EDE6D711294FADF8E7951F4DE6CA56B58",
+        },
+        DirectoryAuthority {
+            name: "gabelmoo".into(),
+            ip: Ipv4Addr::new(131, 188, 40, 189),
+            dir_port: 80,
+            or_port: 443,
+            fingerprint: "F2044413DAC2E02E3D6BCF4735A19BCA1DE97281",
+            v3ident: "ED03BB616EB2F60BEC80151114BB25CEF515B226",
+        },
+        DirectoryAuthority {
+            name: "dannenberg".into(),
+            ip: Ipv4Addr::new(193, 23, 244, 244),
+            dir_port: 80,
+            or_port: 443,
+            fingerprint: "7BE683E65D48141321C5ED92F075C55364AC7123",
+            v3ident: "0232AF901C31A04EE9848595AF9BB7620D4C5B2E",
+        },
+        DirectoryAuthority {
+            name: "maatuska".into(),
+            ip: Ipv4Addr::new(171, 25, 193, 9),
+            dir_port: 443,
+            or_port: 80,
+            fingerprint: "BD6A829255CB08E66FBE7D3748363586E46B3810",
+            v3ident: "49015F787433103580E3B66A1707A00E60F2D15B",
+        },
+        DirectoryAuthority {
+            name: "Faravahar".into(),
+            ip: Ipv4Addr::new(216, 218, 219, 41),
+            dir_port: 80,
+            or_port: 443,
+            fingerprint: "E3E42D35F801C9D5AB23584E0025D56FE2B33396",
+            v3ident: "70849B868D606BAECFB6128C5E3D782029AA394F",
+        },
+        DirectoryAuthority {
+            name: "longclaw".into(),
+            ip: Ipv4Addr::new(199, 58, 81, 140),
+            dir_port: 80,
+            or_port: 443,
+            fingerprint: "74A910646BCEEFBCD2E874FC1DC997430F968145",
+            v3ident: "23D15D965BC35114467363C165C4F724B64B4F66",
+        },
+        DirectoryAuthority {
+            name: "bastet".into(),
+            ip: Ipv4Addr::new(204, 13, 164, 118),
+            dir_port: 80,
+            or_port: 443,
+            fingerprint: "24E2F139121D4394C54B5BCC368B3B411857C413",
+            v3ident: "27102BC123E7AF1D4741AE047E160C91ADC76B21",
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_the_nine_v3_authorities_with_a_dir_port() {
+        let authorities = directory_authorities();
+
+        assert_eq!(9, authorities.len());
+        for da in &authorities {
+            assert_ne!(0, da.dir_port, "{}", da.name);
+            assert!(da.base_url().is_some(), "{}", da.name);
+        }
+    }
+
+    #[test]
+    fn has_no_base_url_without_a_dir_port() {
+        let da = DirectoryAuthority {
+            name: "test".into(),
+            ip: Ipv4Addr::new(192, 0, 2, 1),
+            dir_port: 0,
+            or_port: 443,
+            fingerprint: "",
+            v3ident: "",
+        };
+
+        assert_eq!(None, da.base_url());
+    }
+
+    #[test]
+    fn faravahar_is_at_its_current_address() {
+        let faravahar = directory_authorities()
+            .into_iter()
+            .find(|da| da.name == "Faravahar")
+            .unwrap();
+
+        assert_eq!(
+            Some("http://216.218.219.41:80".to_string()),
+            faravahar.base_url()
+        );
+    }
+
+    #[test]
+    fn fingerprints_are_distinct_from_the_v3_identities() {
+        for da in directory_authorities() {
+            assert_eq!(40, da.fingerprint.len(), "{}", da.name);
+            assert_eq!(40, da.v3ident.len(), "{}", da.name);
+            assert_ne!(da.fingerprint, da.v3ident, "{}", da.name);
+        }
+    }
+}
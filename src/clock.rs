@@ -0,0 +1,44 @@
+use chrono::{DateTime, Utc};
+
+/// The source of the current time.
+/// Abstracted so that time-dependent logic, e.g. cache freshness, can be run at a fixed time.
+pub trait Clock {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that is stopped at the time it was created with.
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::parse_consensus_document;
+    use crate::testing::*;
+
+    #[test]
+    fn a_fixed_clock_decides_the_validity_of_a_consensus() {
+        let consensus = parse_consensus_document(&document_with_relays(1)).unwrap();
+        let clock = FixedClock(datetime(VALID_UNTIL));
+
+        assert_eq!(datetime(VALID_UNTIL), clock.now());
+        assert_eq!(clock.now(), clock.now());
+        assert!(consensus.is_valid_at(&clock.now(), chrono::Duration::zero()));
+        let later = FixedClock(datetime(VALID_UNTIL) + chrono::Duration::seconds(1));
+        assert!(!consensus.is_valid_at(&later.now(), chrono::Duration::zero()));
+    }
+}
@@ -0,0 +1,249 @@
+use crate::consensus::{default_cache_dir, Flavor, DEFAULT_CACHE_SIZE_CAP};
+use chrono::Duration;
+use std::collections::HashMap;
+
+// The default tolerance for the local clock being off when checking the validity of the consensus.
+const DEFAULT_SKEW_TOLERANCE_SECONDS: i64 = 5 * 60;
+// The default timeout of a download, so that an authority that doesn't respond is given up on and
+// the next one is tried.
+const DEFAULT_TIMEOUT_SECONDS: u64 = 15;
+
+/// The settings of the command, taken from environment variables and command line options.
+/// A command line option takes precedence over the corresponding environment variable.
+//
+// | Option                       | Environment variable | Default        |
+// |------------------------------|----------------------|----------------|
+// | --cache-dir <dir>            | GANTZ_CACHE_DIR      | ~/.gants       |
+// | --authority <name>           | GANTZ_AUTHORITY      |                |
+// | --proxy <url>                | GANTZ_PROXY          |                |
+// | --timeout <seconds>          | GANTZ_TIMEOUT        | 15             |
+// | --flavor <flavor>            | GANTZ_FLAVOR         | microdesc      |
+// | --cache-size-cap <n>         |                      | 50 MiB         |
+// | --skew-tolerance <s>         |                      | 300            |
+// | --format <format>            |                      | debug          |
+// | --no-compression             |                      |                |
+// | --offline                    |                      |                |
+// | --url <url>                  |                      |                |
+// | --retries-per-authority <n>  |                      | 1              |
+#[derive(Debug)]
+pub struct Config {
+    pub cache_dir: String,
+    /// The upper bound of the total size of the cache in bytes.
+    pub cache_size_cap: usize,
+    /// The nickname of the directory authority to download the consensus from.
+    pub authority: Option<String>,
+    /// The URL of the proxy to download through, e.g. `socks5://127.0.0.1:9050`.
+    pub proxy: Option<String>,
+    /// The timeout of a download.
+    pub timeout: Option<std::time::Duration>,
+    pub flavor: Flavor,
+    /// Whether to request the compressed consensus. The uncompressed one is easier to inspect
+    /// when debugging.
+    pub compressed: bool,
+    /// Allows for a local clock that is slightly off when checking the validity of the
+    /// consensus.
+    pub skew_tolerance: Duration,
+    pub format: OutputFormat,
+    /// The URL to download the consensus from instead of a directory authority, e.g. of an
+    /// archived consensus.
+    pub url: Option<String>,
+    /// How many times a failed download is retried against the same directory authority.
+    pub retries_per_authority: u32,
+    /// Only use the cached consensus and never access the network.
+    pub offline: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum OutputFormat {
+    Debug,
+    /// Tor's `fingerprint` file format.
+    Fingerprint,
+    /// A JSON summary, see `Consensus::write_json`.
+    Json,
+}
+
+impl Config {
+    pub fn from_env_and_args() -> Result<Config, String> {
+        Config::from_vars_and_args(std::env::vars().collect(), std::env::args().skip(1))
+    }
+
+    // `args` excludes the program name.
+    fn from_vars_and_args<I>(vars: HashMap<String, String>, args: I) -> Result<Config, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut config = Config {
+            cache_dir: default_cache_dir(),
+            cache_size_cap: DEFAULT_CACHE_SIZE_CAP,
+            authority: None,
+            proxy: None,
+            timeout: Some(std::time::Duration::from_secs(DEFAULT_TIMEOUT_SECONDS)),
+            flavor: Flavor::Microdesc,
+            compressed: true,
+            skew_tolerance: Duration::seconds(DEFAULT_SKEW_TOLERANCE_SECONDS),
+            format: OutputFormat::Debug,
+            url: None,
+            retries_per_authority: 1,
+            offline: false,
+        };
+
+        for (name, value) in vars {
+            match name.as_str() {
+                "GANTZ_CACHE_DIR" => config.cache_dir = value,
+                "GANTZ_AUTHORITY" => config.authority = Some(value),
+                "GANTZ_PROXY" => config.proxy = Some(value),
+                "GANTZ_TIMEOUT" => config.timeout = Some(parse_timeout(&value)?),
+                "GANTZ_FLAVOR" => config.flavor = value.parse()?,
+                _ => {}
+            }
+        }
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--no-compression" {
+                config.compressed = false;
+                continue;
+            }
+            if arg == "--offline" {
+                config.offline = true;
+                continue;
+            }
+
+            let value = args
+                .next()
+                .ok_or_else(|| format!("Missing value for {}", arg))?;
+            match arg.as_str() {
+                "--cache-dir" => config.cache_dir = value,
+                "--authority" => config.authority = Some(value),
+                "--proxy" => config.proxy = Some(value),
+                "--timeout" => config.timeout = Some(parse_timeout(&value)?),
+                "--flavor" => config.flavor = value.parse()?,
+                "--url" => config.url = Some(value),
+                "--cache-size-cap" => {
+                    config.cache_size_cap = value
+                        .parse()
+                        .map_err(|_| format!("Invalid cache size cap: {}", value))?;
+                }
+                "--retries-per-authority" => {
+                    config.retries_per_authority = value
+                        .parse()
+                        .map_err(|_| format!("Invalid retries per authority: {}", value))?;
+                }
+                "--skew-tolerance" => {
+                    config.skew_tolerance = Duration::seconds(
+                        value
+                            .parse()
+                            .map_err(|_| format!("Invalid skew tolerance: {}", value))?,
+                    );
+                }
+                "--format" => {
+                    config.format = match value.as_str() {
+                        "debug" => OutputFormat::Debug,
+                        "fingerprint" => OutputFormat::Fingerprint,
+                        "json" => OutputFormat::Json,
+                        _ => return Err(format!("Unknown format: {}", value)),
+                    };
+                }
+                _ => return Err(format!("Unknown option: {}", arg)),
+            }
+        }
+
+        if config.offline && config.url.is_some() {
+            return Err("--url can't be used with --offline".to_string());
+        }
+
+        Ok(config)
+    }
+}
+
+// The timeout is given in seconds.
+fn parse_timeout(s: &str) -> Result<std::time::Duration, String> {
+    s.parse()
+        .map(std::time::Duration::from_secs)
+        .map_err(|_| format!("Invalid timeout: {}", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::{cache_consensus_document, get_consensus_document_from_cache};
+    use crate::testing::*;
+
+    fn vars(vars: &[(&str, &str)]) -> HashMap<String, String> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn takes_the_settings_from_the_environment() {
+        let config = Config::from_vars_and_args(
+            vars(&[
+                ("GANTZ_CACHE_DIR", "/tmp/gantz"),
+                ("GANTZ_AUTHORITY", "moria1"),
+                ("GANTZ_PROXY", "socks5://127.0.0.1:9050"),
+                ("GANTZ_TIMEOUT", "30"),
+                ("GANTZ_FLAVOR", "ns"),
+            ]),
+            args(&[]),
+        )
+        .unwrap();
+
+        assert_eq!("/tmp/gantz", config.cache_dir);
+        assert_eq!(Some("moria1".to_string()), config.authority);
+        assert_eq!(Some("socks5://127.0.0.1:9050".to_string()), config.proxy);
+        assert_eq!(Some(std::time::Duration::from_secs(30)), config.timeout);
+        assert_eq!(Flavor::Ns, config.flavor);
+    }
+
+    #[test]
+    fn prefers_the_options_to_the_environment() {
+        let config = Config::from_vars_and_args(
+            vars(&[
+                ("GANTZ_CACHE_DIR", "/tmp/gantz"),
+                ("GANTZ_AUTHORITY", "moria1"),
+                ("GANTZ_FLAVOR", "ns"),
+            ]),
+            args(&["--authority", "tor26", "--flavor", "microdesc"]),
+        )
+        .unwrap();
+
+        assert_eq!("/tmp/gantz", config.cache_dir);
+        assert_eq!(Some("tor26".to_string()), config.authority);
+        assert_eq!(Flavor::Microdesc, config.flavor);
+    }
+
+    #[tokio::test]
+    async fn caches_in_the_directory_from_the_environment() {
+        let parent = tempfile::tempdir().unwrap();
+        let cache_dir = parent.path().join("gantz");
+        let config = Config::from_vars_and_args(
+            vars(&[("GANTZ_CACHE_DIR", cache_dir.to_str().unwrap())]),
+            args(&[]),
+        )
+        .unwrap();
+
+        cache_consensus_document(
+            &config.cache_dir,
+            config.flavor,
+            &document_with_relays(1),
+            "moria1",
+            &datetime(VALID_UNTIL),
+            config.cache_size_cap,
+        )
+        .await;
+
+        assert!(cache_dir.is_dir());
+        assert!(get_consensus_document_from_cache(
+            cache_dir.to_str().unwrap(),
+            config.flavor,
+            &datetime(VALID_AFTER)
+        )
+        .await
+        .is_some());
+    }
+}
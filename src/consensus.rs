@@ -1,26 +1,174 @@
+use crate::authority::{directory_authorities, DirectoryAuthority};
+use crate::path::Position;
 use bitflags::bitflags;
-use chrono::{DateTime, NaiveDateTime, Utc};
-use std::net::Ipv4Addr;
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use ipnet::Ipv4Net;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::seq::SliceRandom;
+use rand::Rng;
+#[cfg(feature = "serde")]
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
 
 const CACHE_KEY_BODY: &str = "consensus_document_body";
 const CACHE_KEY_VALID_UNTIL: &str = "consensus_document_valid_until";
+const CACHE_KEY_AUTHORITY: &str = "consensus_document_authority";
+#[cfg(feature = "serde")]
+const CACHE_KEY_PARSED: &str = "consensus_parsed";
+// The first byte of the cached parsed consensus. Bumped whenever `Consensus` changes, so that a
+// consensus cached by an earlier version is parsed again instead of misread.
+#[cfg(feature = "serde")]
+const PARSED_CACHE_SCHEMA_VERSION: u8 = 2;
 const ONION_ROUTER_LIMIT: usize = 100;
+const LINE_EXCERPT_LIMIT: usize = 200;
+// The default of the "bwweightscale" parameter the bandwidth weights are scaled by.
+const DEFAULT_BW_WEIGHT_SCALE: i64 = 10000;
+// The default of the "guard-lifetime-days" parameter.
+const DEFAULT_GUARD_LIFETIME_DAYS: i64 = 120;
+// The oldest consensus method that is understood. Tor doesn't support older ones either.
+const MIN_SUPPORTED_CONSENSUS_METHOD: u32 = 28;
+/// The default upper bound of the total size of the cache in bytes.
+pub(crate) const DEFAULT_CACHE_SIZE_CAP: usize = 50 * 1024 * 1024;
 
-fn cache_dir() -> String {
-    format!("{}/.gants", dirs::home_dir().unwrap().display())
+/// `~/.gants`, or `.gants` in the temporary directory where there is no home directory, e.g. in
+/// some containers.
+pub(crate) fn default_cache_dir() -> String {
+    cache_dir_under(dirs::home_dir())
 }
 
-pub(crate) async fn cache_consensus_document(consensus: &String, valid_until: &DateTime<Utc>) {
-    cacache::write(cache_dir(), CACHE_KEY_BODY, consensus)
+fn cache_dir_under(home: Option<std::path::PathBuf>) -> String {
+    home.unwrap_or_else(std::env::temp_dir)
+        .join(".gants")
+        .display()
+        .to_string()
+}
+
+// The cache keys of the consensus document are per flavor.
+fn cache_key(key: &str, flavor: Flavor) -> String {
+    format!("{}_{}", key, flavor.name())
+}
+
+/// Caches the consensus document along with the name of the directory authority it was
+/// downloaded from, then evicts old cache entries if the cache has grown beyond `size_cap` bytes.
+pub(crate) async fn cache_consensus_document(
+    cache_dir: &str,
+    flavor: Flavor,
+    consensus: &str,
+    authority: &str,
+    valid_until: &DateTime<Utc>,
+    size_cap: usize,
+) {
+    cacache::write(cache_dir, cache_key(CACHE_KEY_BODY, flavor), consensus)
         .await
         .unwrap();
-    cacache::write(cache_dir(), CACHE_KEY_VALID_UNTIL, valid_until.to_rfc3339())
+    cacache::write(cache_dir, cache_key(CACHE_KEY_AUTHORITY, flavor), authority)
+        .await
+        .unwrap();
+    cacache::write(
+        cache_dir,
+        cache_key(CACHE_KEY_VALID_UNTIL, flavor),
+        valid_until.to_rfc3339(),
+    )
+    .await
+    .unwrap();
+    evict_cache_entries(cache_dir, size_cap).await;
+}
+
+/// Caches the consensus parsed from `document` in a compact binary format alongside the document,
+/// so that a later run doesn't need to parse the document again. The SHA-256 digest of `document`
+/// is stored with it, so that it's only used for the same document.
+#[cfg(feature = "serde")]
+pub(crate) async fn cache_parsed_consensus(
+    cache_dir: &str,
+    flavor: Flavor,
+    document: &str,
+    consensus: &Consensus,
+) {
+    let mut bytes = vec![PARSED_CACHE_SCHEMA_VERSION];
+    bytes.extend(Sha256::digest(document.as_bytes()));
+    bytes.extend(bincode::serialize(consensus).unwrap());
+    cacache::write(cache_dir, cache_key(CACHE_KEY_PARSED, flavor), bytes)
         .await
         .unwrap();
 }
 
-pub(crate) async fn get_consensus_document_from_cache(now: &DateTime<Utc>) -> Option<String> {
-    let valid_until = match cacache::read(cache_dir(), CACHE_KEY_VALID_UNTIL).await {
+/// The consensus cached by `cache_parsed_consensus`. `None` if there is none, it was cached
+/// with another `PARSED_CACHE_SCHEMA_VERSION`, or it wasn't parsed from `document`, e.g. because
+/// the document was replaced without it.
+#[cfg(feature = "serde")]
+pub(crate) async fn get_parsed_consensus_from_cache(
+    cache_dir: &str,
+    flavor: Flavor,
+    document: &str,
+) -> Option<Consensus> {
+    let bytes = cacache::read(cache_dir, cache_key(CACHE_KEY_PARSED, flavor))
+        .await
+        .ok()?;
+    let rest = match bytes.split_first() {
+        Some((&PARSED_CACHE_SCHEMA_VERSION, rest)) => rest,
+        _ => return None,
+    };
+    let digest = Sha256::digest(document.as_bytes());
+    match rest.strip_prefix(digest.as_slice()) {
+        Some(serialized) => bincode::deserialize(serialized).ok(),
+        None => None,
+    }
+}
+
+/// Removes the least recently written cache entries until the total size of the cache is within
+/// `size_cap` bytes.
+pub(crate) async fn evict_cache_entries(cache_dir: &str, size_cap: usize) {
+    let mut entries = cacache::list_sync(cache_dir)
+        .filter_map(Result::ok)
+        .collect::<Vec<_>>();
+    let mut total_size = entries.iter().map(|entry| entry.size).sum::<usize>();
+    if total_size <= size_cap {
+        return;
+    }
+
+    entries.sort_by_key(|entry| entry.time);
+    let mut evicted = 0;
+    while total_size > size_cap && evicted < entries.len() {
+        let entry = &entries[evicted];
+        cacache::remove(cache_dir, &entry.key).await.unwrap();
+        total_size -= entry.size;
+        evicted += 1;
+
+        // The content is shared by entries with identical data, so it can only be removed once no
+        // remaining entry refers to it.
+        if !entries[evicted..]
+            .iter()
+            .any(|e| e.integrity == entry.integrity)
+        {
+            cacache::remove_hash(cache_dir, &entry.integrity)
+                .await
+                .unwrap();
+        }
+    }
+}
+
+/// A consensus document read from the cache.
+#[derive(Debug)]
+pub(crate) struct CachedConsensus {
+    pub(crate) body: String,
+    /// The name of the directory authority the document was downloaded from. `None` if it was
+    /// cached without one.
+    pub(crate) authority: Option<String>,
+}
+
+pub(crate) async fn get_consensus_document_from_cache(
+    cache_dir: &str,
+    flavor: Flavor,
+    now: &DateTime<Utc>,
+) -> Option<CachedConsensus> {
+    let valid_until = match cacache::read(cache_dir, cache_key(CACHE_KEY_VALID_UNTIL, flavor)).await
+    {
         Ok(s) => {
             let valid_until_string = String::from_utf8(s).unwrap();
             DateTime::parse_from_rfc3339(&valid_until_string).unwrap()
@@ -35,179 +183,3635 @@ pub(crate) async fn get_consensus_document_from_cache(now: &DateTime<Utc>) -> Op
         return None;
     }
 
-    Some(String::from_utf8(cacache::read(cache_dir(), CACHE_KEY_BODY).await.unwrap()).unwrap())
+    // The body may have been evicted independently of valid-until.
+    let body = match cacache::read(cache_dir, cache_key(CACHE_KEY_BODY, flavor)).await {
+        Ok(body) => String::from_utf8(body).unwrap(),
+        Err(e) => {
+            println!("{:?}", e);
+            return None;
+        }
+    };
+    let authority = cacache::read(cache_dir, cache_key(CACHE_KEY_AUTHORITY, flavor))
+        .await
+        .ok()
+        .and_then(|authority| String::from_utf8(authority).ok());
+
+    Some(CachedConsensus { body, authority })
+}
+
+/// Options for `parse_consensus_document_with_options`.
+#[derive(Debug, Default)]
+pub struct ParseOptions {
+    /// Reject the document if an onion router has an inconsistent set of flags.
+    /// See `OnionRouter::validate_flags`.
+    pub strict_flags: bool,
+    /// Skip onion routers published later than this after valid-after, which indicates a
+    /// misconfigured or malicious descriptor.
+    pub max_publication_skew: Option<Duration>,
+    /// Reject the document instead of skipping an onion router, e.g. one that isn't stable (see
+    /// `OnionRouter::is_stable`), or ignoring a flag that isn't known.
+    pub warnings_as_errors: bool,
+    /// Reject the document if the onion routers aren't sorted by identity as the spec requires.
+    pub sorted_relays: bool,
+    /// Skip onion routers with the identity of one of the directory authorities' relays (see
+    /// `crate::authority::directory_authorities`) but another address or OR port, or without the
+    /// Authority flag, since no other onion router may reuse the identity.
+    pub skip_authority_impostors: bool,
+    /// Collect the lines with a keyword that isn't parsed into `Consensus::unknown_lines`, e.g.
+    /// to discover fields added to the spec.
+    pub collect_unknown: bool,
+}
+
+pub fn parse_consensus_document(consensus: &str) -> Result<Consensus, ParseError> {
+    parse_consensus_document_with_options(consensus, &ParseOptions::default())
+}
+
+pub fn parse_consensus_document_with_options(
+    consensus: &str,
+    options: &ParseOptions,
+) -> Result<Consensus, ParseError> {
+    let (consensus, _) = parse_and_collect(consensus.lines().map(Ok), options)?;
+    Ok(consensus)
+}
+
+/// Parses consensus documents concatenated in `body`, e.g. in an archive. Each document starts
+/// with its "network-status-version" line.
+pub fn parse_consensus_documents(body: &str) -> Vec<Result<Consensus, ParseError>> {
+    let mut documents = vec![];
+    let mut document = String::new();
+    for line in body.lines() {
+        if line.starts_with("network-status-version") && !document.is_empty() {
+            documents.push(std::mem::take(&mut document));
+        }
+        document.push_str(line);
+        document.push('\n');
+    }
+    if !document.is_empty() {
+        documents.push(document);
+    }
+
+    documents
+        .iter()
+        .map(|document| parse_consensus_document_with_options(document, &ParseOptions::default()))
+        .collect()
+}
+
+/// Same as `parse_consensus_document_with_options`, also returning statistics about the parsing.
+pub fn parse_consensus_document_with_stats(
+    consensus: &str,
+    options: &ParseOptions,
+) -> Result<(Consensus, ParseStats), ParseError> {
+    parse_and_collect(consensus.lines().map(Ok), options)
+}
+
+/// Parses the consensus document as it is read from `reader`.
+pub fn parse_consensus_document_from_reader<R: BufRead>(
+    reader: R,
+    options: &ParseOptions,
+) -> Result<Consensus, ReadError> {
+    let (consensus, _) = parse_and_collect(
+        reader.lines().map(|line| line.map_err(ReadError::Io)),
+        options,
+    )?;
+    Ok(consensus)
+}
+
+/// Parses the consensus document, passing each onion router to `f` instead of keeping them.
+/// The returned `Consensus` has no onion routers.
+pub fn parse_consensus_foreach<F>(consensus: &str, mut f: F) -> Result<Consensus, ParseError>
+where
+    F: FnMut(OnionRouter),
+{
+    let (consensus, _) = parse(consensus.lines().map(Ok), &ParseOptions::default(), |or| {
+        f(or);
+        true
+    })?;
+    Ok(consensus)
+}
+
+/// Statistics about parsing a consensus document.
+#[derive(Debug, Default)]
+pub struct ParseStats {
+    pub lines_processed: usize,
+    /// The number of onion routers kept in the consensus.
+    pub relays_kept: usize,
+    /// The number of onion routers dropped because they aren't stable or were filtered out by
+    /// the options.
+    pub relays_skipped: usize,
+    /// The number of bytes of the lines processed, counting one byte per line terminator.
+    pub bytes_processed: usize,
+    /// The wall time of the parsing.
+    pub duration: std::time::Duration,
+    /// Why onion routers were skipped by the options, e.g. for being published in the future.
+    pub warnings: Vec<String>,
+}
+
+impl ParseStats {
+    /// The parsing throughput in megabytes (10^6 bytes) per second.
+    pub fn throughput_mb_per_sec(&self) -> f64 {
+        let secs = self.duration.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+
+        self.bytes_processed as f64 / 1_000_000.0 / secs
+    }
+}
+
+// Collects the onion routers, up to `ONION_ROUTER_LIMIT`.
+fn parse_and_collect<I, L, E>(
+    lines: I,
+    options: &ParseOptions,
+) -> Result<(Consensus, ParseStats), E>
+where
+    I: Iterator<Item = Result<L, E>>,
+    L: AsRef<str>,
+    E: From<ParseError>,
+{
+    let mut onion_routers = vec![];
+    let (mut consensus, stats) = parse(lines, options, |or| {
+        onion_routers.push(or);
+        onion_routers.len() < ONION_ROUTER_LIMIT
+    })?;
+    consensus.onion_routers = onion_routers;
+
+    Ok((consensus, stats))
 }
 
 // https://github.com/torproject/torspec/blob/main/dir-spec.txt
 // 3.4.1. Vote and consensus status document formats
-pub(crate) fn parse_consensus_document(consensus: &String) -> Result<Consensus, ParseError> {
+//
+// Each onion router that passes the filters is handed to `on_onion_router`, which returns whether
+// to keep collecting onion routers. The entries of the rest are skipped, but the footer is still
+// parsed.
+fn parse<I, L, E, F>(
+    lines: I,
+    options: &ParseOptions,
+    mut on_onion_router: F,
+) -> Result<(Consensus, ParseStats), E>
+where
+    I: Iterator<Item = Result<L, E>>,
+    L: AsRef<str>,
+    E: From<ParseError>,
+    F: FnMut(OnionRouter) -> bool,
+{
+    let started_at = std::time::Instant::now();
+    let mut stats = ParseStats::default();
+    let mut flavor = Flavor::Microdesc;
+    let mut consensus_method = None;
     let mut valid_after = None;
+    let mut fresh_until = None;
     let mut valid_until = None;
+    let mut shared_random_value = None;
+    let mut client_versions = vec![];
+    let mut server_versions = vec![];
+    let mut bandwidth_file_headers = None;
+    let mut bandwidth_file_digest = None;
+    let mut params = HashMap::new();
+    let mut bandwidth_weights = None;
+    let mut signing_authorities = vec![];
+    let authorities = if options.skip_authority_impostors {
+        directory_authorities()
+    } else {
+        vec![]
+    };
+    let mut in_header = true;
+    let mut truncated = false;
+    let mut last_identity: Option<Vec<u8>> = None;
+    let mut header = vec![];
+    let mut footer = vec![];
+    let mut unknown_lines = vec![];
     let mut tmp_onion_router: Option<OnionRouter> = None;
-    let mut onion_routers = vec![];
 
-    for line in consensus.lines() {
-        let strs = line.split_whitespace().collect::<Vec<_>>();
+    for line in lines {
+        let line = line?;
+        stats.lines_processed += 1;
+        stats.bytes_processed += line.as_ref().len() + 1;
+        let mut strs = line.as_ref().split_whitespace().collect::<Vec<_>>();
+        // Blank lines, e.g. separating the documents of an archive, carry nothing.
+        if strs.is_empty() {
+            continue;
+        }
+        // Older documents prefix some lines with "opt", which is otherwise ignored.
+        if strs.len() > 1 && strs[0] == "opt" {
+            strs.remove(0);
+        }
+        // Past the limit, the entries of the remaining onion routers are skipped, but the footer
+        // is still parsed.
+        if truncated && footer.is_empty() && strs[0] != "directory-footer" {
+            continue;
+        }
+        // Keep the lines around the onion routers as they are so that the document can be written
+        // back out.
+        if strs[0] == "directory-footer" || !footer.is_empty() {
+            footer.push(line.as_ref().to_string());
+        }
+        if strs[0] == "r" || strs[0] == "directory-footer" {
+            in_header = false;
+        }
+        if in_header {
+            header.push(line.as_ref().to_string());
+        }
         match strs[0] {
+            // "network-status-version" SP version [SP flavor] NL
             "network-status-version" => {
-                assert_eq!(3, strs.len());
-                if strs[1] != "3" || strs[2] != "microdesc" {
-                    return Err(ParseError::UnsupportedDocumentFormatVersion(String::from(
-                        strs[1],
-                    )));
-                }
+                flavor = match strs[1..] {
+                    ["3"] => Flavor::Ns,
+                    ["3", "microdesc"] => Flavor::Microdesc,
+                    _ => {
+                        return Err(ParseError::UnsupportedDocumentFormatVersion(excerpt(
+                            line.as_ref(),
+                        ))
+                        .into())
+                    }
+                };
             }
             "vote-status" => {
-                assert_eq!(2, strs.len());
-                if strs[1] != "consensus" {
-                    return Err(ParseError::UnexpectedVoteStatus(String::from(strs[1])));
+                if strs[1..] != ["consensus"] {
+                    return Err(ParseError::UnexpectedVoteStatus(excerpt(line.as_ref())).into());
                 }
             }
-            // TODO: consensus-methods
-            // TODO: consensus-method
-            "valid-after" => {
-                assert_eq!(3, strs.len());
-                match NaiveDateTime::parse_from_str(
-                    &format!("{} {}", strs[1], strs[2]),
-                    "%Y-%m-%d %H:%M:%S",
-                ) {
-                    Ok(datetime) => valid_after = Some(DateTime::<Utc>::from_utc(datetime, Utc)),
-                    Err(e) => {
-                        return Err(ParseError::DateTimeParseError("valid-after".to_string(), e))
-                    }
-                }
+            // "consensus-methods" is only in votes, which are rejected above.
+            //
+            // "consensus-method" SP Integer NL
+            //    [At start, exactly once for consensuses.]
+            "consensus-method" => {
+                let method = strs
+                    .get(1)
+                    .and_then(|method| method.parse::<u32>().ok())
+                    .filter(|method| *method >= MIN_SUPPORTED_CONSENSUS_METHOD)
+                    .ok_or_else(|| {
+                        ParseError::UnsupportedConsensusMethod(excerpt(line.as_ref()))
+                    })?;
+                consensus_method = Some(method);
             }
-            "valid-until" => {
-                assert_eq!(3, strs.len());
-                match NaiveDateTime::parse_from_str(
-                    &format!("{} {}", strs[1], strs[2]),
-                    "%Y-%m-%d %H:%M:%S",
-                ) {
-                    Ok(datetime) => valid_until = Some(DateTime::<Utc>::from_utc(datetime, Utc)),
-                    Err(e) => {
-                        return Err(ParseError::DateTimeParseError("valid-until".to_string(), e))
-                    }
+            "valid-after" => valid_after = Some(parse_datetime(line.as_ref(), &strs)?),
+            "fresh-until" => fresh_until = Some(parse_datetime(line.as_ref(), &strs)?),
+            "valid-until" => valid_until = Some(parse_datetime(line.as_ref(), &strs)?),
+            // "client-versions" SP VersionList NL
+            // "server-versions" SP VersionList NL
+            //    VersionList is a comma-separated list of Tor versions.
+            "client-versions" => client_versions = parse_versions(&strs),
+            "server-versions" => server_versions = parse_versions(&strs),
+            // "bandwidth-file-headers" SP KeyValues NL
+            //    KeyValues are the headers of the bandwidth file the votes were made with.
+            "bandwidth-file-headers" => bandwidth_file_headers = Some(parse_key_values(&strs[1..])),
+            // "bandwidth-file-digest" 1*(SP algorithm "=" digest) NL
+            "bandwidth-file-digest" => bandwidth_file_digest = Some(parse_key_values(&strs[1..])),
+            // "params" SP [Parameters] NL
+            //    Parameters ::= Parameter | Parameters SP Parameter
+            //    Parameter ::= Keyword '=' Int32
+            "params" => {
+                params = parse_integer_key_values(&strs[1..])
+                    .ok_or_else(|| ParseError::InvalidParameters(excerpt(line.as_ref())))?;
+            }
+            // "bandwidth-weights" [SP Weights] NL
+            //    Weight ::= Keyword '=' Int32
+            "bandwidth-weights" => {
+                bandwidth_weights = Some(
+                    parse_integer_key_values(&strs[1..])
+                        .ok_or_else(|| ParseError::InvalidParameters(excerpt(line.as_ref())))?,
+                );
+            }
+            // "directory-signature" [SP Algorithm] SP identity SP signing-key-digest NL
+            //    identity is the hex-encoded fingerprint of the authority's identity key.
+            "directory-signature" => {
+                if let Some(identity) = strs.iter().rev().nth(1) {
+                    signing_authorities.push(identity.to_string());
                 }
             }
+            // "shared-rand-current-value" SP NumReveals SP Value NL
+            "shared-rand-current-value" => {
+                let value = strs
+                    .get(2)
+                    .and_then(|value| base64::decode(value).ok())
+                    .ok_or_else(|| ParseError::InvalidSharedRandomValue(excerpt(line.as_ref())))?;
+                shared_random_value = Some(value);
+            }
             "r" => {
-                if let Some(or) = tmp_onion_router {
-                    if or.is_stable() {
-                        onion_routers.push(or);
-                        if onion_routers.len() >= ONION_ROUTER_LIMIT {
-                            tmp_onion_router = None;
-                            break;
-                        }
+                if let Some(or) = tmp_onion_router.take() {
+                    if !finish_onion_router(
+                        or,
+                        options,
+                        valid_after.as_ref(),
+                        &authorities,
+                        &mut stats,
+                        &mut on_onion_router,
+                    )? {
+                        truncated = true;
                     }
                 }
+                if truncated {
+                    continue;
+                }
+                // ns:
                 // "r" SP nickname SP identity SP digest SP publication SP IP SP ORPort SP DirPort
                 //         NL
-                tmp_onion_router = Some(OnionRouter {
+                // microdesc:
+                // "r" SP nickname SP identity SP publication SP IP SP ORPort SP DirPort NL
+                let malformed = || ParseError::MalformedRelayLine(excerpt(line.as_ref()));
+                let invalid_address = || ParseError::InvalidAddress(excerpt(line.as_ref()));
+                // The identity and the digest are base64-encoded with the trailing "=" signs
+                // removed.
+                let decode = |s| base64::decode_config(s, base64::STANDARD_NO_PAD);
+                let (strs, digest) = match flavor {
+                    Flavor::Ns if strs.len() == 9 => (
+                        [&strs[..3], &strs[4..]].concat(),
+                        Some(decode(strs[3]).map_err(|_| malformed())?),
+                    ),
+                    Flavor::Microdesc if strs.len() == 8 => (strs, None),
+                    _ => return Err(malformed().into()),
+                };
+                let ip = strs[5].parse().map_err(|_| invalid_address())?;
+                let or_port = strs[6].parse().map_err(|_| invalid_address())?;
+                let or = OnionRouter {
                     nickname: strs[1].to_string(),
-                    ip: strs[5].parse().expect("valid IPv4 address"),
-                    or_port: strs[6].parse().expect("valid (OR) port number"),
-                    dir_port: strs[7].parse().expect("valid (Dir) port number"),
+                    identity: decode(strs[2]).map_err(|_| malformed())?,
+                    digest,
+                    publication: parse_timestamp(
+                        line.as_ref(),
+                        &format!("{} {}", strs[3], strs[4]),
+                    )?,
+                    ip,
+                    or_port,
+                    or_addresses: vec![SocketAddr::V4(SocketAddrV4::new(ip, or_port))],
+                    dir_port: strs[7].parse().map_err(|_| invalid_address())?,
                     flags: Flags::empty(),
-                });
+                    exit_policy: None,
+                    protocols: Protocols::default(),
+                    version: None,
+                    bandwidth: None,
+                    measured: None,
+                    unmeasured: false,
+                    microdesc_digest: None,
+                    ed25519_identity: None,
+                    family: vec![],
+                };
+                if options.sorted_relays {
+                    // The entries are sorted by identity in ascending order.
+                    if last_identity
+                        .as_ref()
+                        .is_some_and(|last| or.identity <= *last)
+                    {
+                        return Err(ParseError::UnsortedRelays(or.nickname).into());
+                    }
+                    last_identity = Some(or.identity.clone());
+                }
+                tmp_onion_router = Some(or);
+            }
+            // "a" SP address ":" port NL
+            //    [Any number]
+            //    The address is an IPv6 address in brackets or an IPv4 address.
+            "a" => {
+                if let Some(or) = tmp_onion_router.as_mut() {
+                    let address = strs
+                        .get(1)
+                        .and_then(|address| address.parse().ok())
+                        .ok_or_else(|| ParseError::InvalidAddress(excerpt(line.as_ref())))?;
+                    or.or_addresses.push(address);
+                } else {
+                    return Err(ParseError::NoPrecedingRelay(excerpt(line.as_ref())).into());
+                }
             }
             // A series of space-separated status flags.
             "s" => {
                 if let Some(or) = tmp_onion_router.as_mut() {
-                    for flag_index in 1..strs.len() {
-                        or.flags.insert(strs[flag_index].into());
+                    for name in &strs[1..] {
+                        match Flags::try_from(*name) {
+                            Ok(flag) => or.flags.insert(flag),
+                            Err(e) if options.warnings_as_errors => {
+                                return Err(ParseError::InvalidFlags(or.nickname.clone(), e).into())
+                            }
+                            // Flags introduced after this was written are ignored.
+                            Err(_) => {}
+                        }
+                    }
+                } else {
+                    return Err(ParseError::NoPrecedingRelay(excerpt(line.as_ref())).into());
+                }
+            }
+            // "p" SP ("accept" / "reject") SP PortList NL
+            "p" => {
+                if let Some(or) = tmp_onion_router.as_mut() {
+                    or.exit_policy =
+                        Some(ExitPolicy::parse(&strs[1..]).ok_or_else(|| {
+                            ParseError::InvalidExitPolicy(excerpt(line.as_ref()))
+                        })?);
+                } else {
+                    return Err(ParseError::NoPrecedingRelay(excerpt(line.as_ref())).into());
+                }
+            }
+            // "m" SP digest NL
+            "m" => {
+                if let Some(or) = tmp_onion_router.as_mut() {
+                    or.microdesc_digest = strs.get(1).map(|digest| digest.to_string());
+                } else {
+                    return Err(ParseError::NoPrecedingRelay(excerpt(line.as_ref())).into());
+                }
+            }
+            // "v" SP version NL
+            //    The version of the Tor software that the relay is running, e.g. "Tor 0.4.7.13".
+            "v" => {
+                if let Some(or) = tmp_onion_router.as_mut() {
+                    or.version = Some(strs[1..].join(" "));
+                } else {
+                    return Err(ParseError::NoPrecedingRelay(excerpt(line.as_ref())).into());
+                }
+            }
+            // "pr" SP Entries NL
+            "pr" => {
+                if let Some(or) = tmp_onion_router.as_mut() {
+                    or.protocols = Protocols::parse(&strs[1..])
+                        .ok_or_else(|| ParseError::InvalidProtocols(excerpt(line.as_ref())))?;
+                } else {
+                    return Err(ParseError::NoPrecedingRelay(excerpt(line.as_ref())).into());
+                }
+            }
+            // "w" SP "Bandwidth=" INT [SP "Measured=" INT] [SP "Unmeasured=1"] NL
+            "w" => {
+                if let Some(or) = tmp_onion_router.as_mut() {
+                    for key_value in &strs[1..] {
+                        match key_value.split_once('=') {
+                            Some(("Bandwidth", v)) => {
+                                or.bandwidth = Some(v.parse().map_err(|_| {
+                                    ParseError::InvalidBandwidth(excerpt(line.as_ref()))
+                                })?);
+                            }
+                            Some(("Measured", v)) => {
+                                or.measured = Some(v.parse().map_err(|_| {
+                                    ParseError::InvalidBandwidth(excerpt(line.as_ref()))
+                                })?);
+                            }
+                            Some(("Unmeasured", "1")) => or.unmeasured = true,
+                            // Other keywords may be added in the future.
+                            _ => {}
+                        }
                     }
                 } else {
-                    panic!("No tmp_onion_router exists");
+                    return Err(ParseError::NoPrecedingRelay(excerpt(line.as_ref())).into());
                 }
             }
             _ => {
-                // TODO
+                if options.collect_unknown {
+                    unknown_lines.push((stats.lines_processed, line.as_ref().to_string()));
+                }
             }
         }
     }
 
     if let Some(or) = tmp_onion_router {
-        if or.is_stable() {
-            onion_routers.push(or);
+        finish_onion_router(
+            or,
+            options,
+            valid_after.as_ref(),
+            &authorities,
+            &mut stats,
+            &mut on_onion_router,
+        )?;
+    }
+
+    let consensus = Consensus {
+        flavor,
+        consensus_method: consensus_method.ok_or(ParseError::MissingField("consensus-method"))?,
+        valid_after: valid_after.ok_or(ParseError::MissingField("valid-after"))?,
+        fresh_until: fresh_until.ok_or(ParseError::MissingField("fresh-until"))?,
+        valid_until: valid_until.ok_or(ParseError::MissingField("valid-until"))?,
+        shared_random_value,
+        client_versions,
+        server_versions,
+        bandwidth_file_headers,
+        bandwidth_file_digest,
+        params,
+        bandwidth_weights,
+        signing_authorities,
+        header,
+        footer,
+        truncated,
+        unknown_lines,
+        onion_routers: vec![],
+    };
+    stats.duration = started_at.elapsed();
+
+    Ok((consensus, stats))
+}
+
+// Parses the timestamp following a keyword, e.g. "valid-after" SP YYYY-MM-DD SP HH:MM:SS NL
+// `strs` are the fields of the line.
+fn parse_datetime(line: &str, strs: &[&str]) -> Result<DateTime<Utc>, ParseError> {
+    // Parsing the fields together fails on a missing or an extra one as on a malformed timestamp.
+    parse_timestamp(line, &strs[1..].join(" "))
+}
+
+// Splits the VersionList following the keyword. An empty list may have no field at all.
+fn parse_versions(strs: &[&str]) -> Vec<String> {
+    strs.get(1)
+        .map(|list| list.split(',').map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+// Parses the space-separated Key=Value pairs. A pair without "=" has an empty value.
+fn parse_key_values(strs: &[&str]) -> HashMap<String, String> {
+    strs.iter()
+        .map(|key_value| match key_value.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => (key_value.to_string(), String::new()),
+        })
+        .collect()
+}
+
+// Parses the space-separated Keyword=Int32 pairs, e.g. of the "params" line.
+fn parse_integer_key_values(strs: &[&str]) -> Option<HashMap<String, i32>> {
+    strs.iter()
+        .map(|key_value| {
+            let (key, value) = key_value.split_once('=')?;
+            Some((key.to_string(), value.parse().ok()?))
+        })
+        .collect()
+}
+
+// `line` is the line the timestamp is in, for the error.
+fn parse_timestamp(line: &str, timestamp: &str) -> Result<DateTime<Utc>, ParseError> {
+    match NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S") {
+        Ok(datetime) => Ok(DateTime::<Utc>::from_utc(datetime, Utc)),
+        Err(e) => Err(ParseError::DateTimeParseError(excerpt(line), e)),
+    }
+}
+
+// The line as it is included in a `ParseError`, cut off after `LINE_EXCERPT_LIMIT` characters so
+// that an overlong line doesn't flood the output.
+fn excerpt(line: &str) -> String {
+    match line.char_indices().nth(LINE_EXCERPT_LIMIT) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None => line.to_string(),
+    }
+}
+
+// Called once all the lines of an onion router's entry have been parsed.
+// Returns whether to keep collecting onion routers.
+fn finish_onion_router<F>(
+    or: OnionRouter,
+    options: &ParseOptions,
+    valid_after: Option<&DateTime<Utc>>,
+    authorities: &[DirectoryAuthority],
+    stats: &mut ParseStats,
+    on_onion_router: &mut F,
+) -> Result<bool, ParseError>
+where
+    F: FnMut(OnionRouter) -> bool,
+{
+    if options.strict_flags {
+        or.validate_flags()
+            .map_err(|e| ParseError::InvalidFlags(or.nickname.clone(), e))?;
+    }
+
+    if let (Some(max_skew), Some(valid_after)) = (options.max_publication_skew, valid_after) {
+        if or.publication > *valid_after + max_skew {
+            if options.warnings_as_errors {
+                return Err(ParseError::PublishedInFuture(or.nickname, or.publication));
+            }
+            stats.warnings.push(format!(
+                "Skipped onion router {} published in the future: {}",
+                or.nickname, or.publication
+            ));
+            stats.relays_skipped += 1;
+            return Ok(true);
+        }
+    }
+
+    // The authorities' own relays are in the consensus too, so only an onion router that has an
+    // authority's identity but not its address or the Authority flag is an impostor.
+    let fingerprint = or.fingerprint();
+    if authorities.iter().any(|da| {
+        da.fingerprint == fingerprint
+            && (da.ip != or.ip || da.or_port != or.or_port || !or.flags.contains(Flags::AUTHORITY))
+    }) {
+        if options.warnings_as_errors {
+            return Err(ParseError::AuthorityIdentityReused(or.nickname));
+        }
+        stats.warnings.push(format!(
+            "Skipped onion router {} with the identity of a directory authority",
+            or.nickname
+        ));
+        stats.relays_skipped += 1;
+        return Ok(true);
+    }
+
+    if !or.is_stable() {
+        if options.warnings_as_errors {
+            return Err(ParseError::SkippedRelay(or.nickname));
         }
+        stats.relays_skipped += 1;
+        return Ok(true);
     }
 
-    Ok(Consensus {
-        valid_after: valid_after.unwrap(),
-        valid_until: valid_until.unwrap(),
-        onion_routers,
-    })
+    stats.relays_kept += 1;
+    Ok(on_onion_router(or))
 }
 
+/// The errors pertaining to a line hold the line, cut off if it is long.
 #[derive(Debug)]
-pub(crate) enum ParseError {
+pub enum ParseError {
     UnsupportedDocumentFormatVersion(String),
     UnexpectedVoteStatus(String),
+    /// The "consensus-method" line names a method older than `MIN_SUPPORTED_CONSENSUS_METHOD`,
+    /// or isn't a number.
+    UnsupportedConsensusMethod(String),
     DateTimeParseError(String, chrono::ParseError),
+    /// The onion router (identified by its nickname) has an inconsistent set of flags, or a flag
+    /// that isn't known with `ParseOptions::warnings_as_errors`.
+    InvalidFlags(String, FlagError),
+    /// The "p" line couldn't be parsed.
+    InvalidExitPolicy(String),
+    /// The "pr" line couldn't be parsed.
+    InvalidProtocols(String),
+    /// The "w" line couldn't be parsed.
+    InvalidBandwidth(String),
+    /// The "params" or the "bandwidth-weights" line couldn't be parsed.
+    InvalidParameters(String),
+    /// The "shared-rand-current-value" line couldn't be parsed.
+    InvalidSharedRandomValue(String),
+    /// The onion router (identified by its nickname) was published too far after valid-after.
+    /// Only with `ParseOptions::warnings_as_errors`.
+    PublishedInFuture(String, DateTime<Utc>),
+    /// The onion router (identified by its nickname) would be skipped, e.g. because it isn't
+    /// stable. Only with `ParseOptions::warnings_as_errors`.
+    SkippedRelay(String),
+    /// The onion router (identified by its nickname) is out of order by identity.
+    /// Only with `ParseOptions::sorted_relays`.
+    UnsortedRelays(String),
+    /// The onion router (identified by its nickname) has the identity of a directory authority.
+    /// Only with `ParseOptions::skip_authority_impostors` and `warnings_as_errors`.
+    AuthorityIdentityReused(String),
+    /// A line that belongs to an onion router's entry doesn't follow an "r" line.
+    NoPrecedingRelay(String),
+    /// The "r" line has too few or too many fields, or an undecodable identity or digest.
+    MalformedRelayLine(String),
+    /// The onion router (identified by its nickname) has an empty identity.
+    /// Only from `Consensus::check_identities`.
+    EmptyIdentity(String),
+    /// The IP address or a port in the "r" line couldn't be parsed.
+    InvalidAddress(String),
+    /// The document lacks a required line, e.g. "valid-after".
+    MissingField(&'static str),
+}
+
+/// What `Consensus::relays_sorted` sorts the onion routers by.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Identity,
+    Nickname,
+    /// Onion routers without a "w" line come first.
+    Bandwidth,
+    Ip,
+}
+
+/// Why a consensus isn't valid at a time, see `Consensus::check_validity_at`.
+#[derive(Debug, PartialEq)]
+pub enum ValidityError {
+    /// The time is before valid-after.
+    NotYetValid(DateTime<Utc>),
+    /// The time is after valid-until.
+    Expired(DateTime<Utc>),
 }
 
 #[derive(Debug)]
-pub(crate) struct Consensus {
+pub enum ReadError {
+    /// Reading the document failed, e.g. the stream was cut off.
+    Io(std::io::Error),
+    Parse(ParseError),
+}
+
+impl From<ParseError> for ReadError {
+    fn from(e: ParseError) -> Self {
+        ReadError::Parse(e)
+    }
+}
+
+/// The flavor of a consensus document.
+// https://github.com/torproject/torspec/blob/main/dir-spec.txt
+// 3.9. Computing a consensus from a set of votes
+//    The unflavored consensus document is called "ns". The microdescriptor flavored one is
+//    called "microdesc".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Flavor {
+    Ns,
+    Microdesc,
+}
+
+impl Flavor {
+    pub fn name(self) -> &'static str {
+        match self {
+            Flavor::Ns => "ns",
+            Flavor::Microdesc => "microdesc",
+        }
+    }
+}
+
+impl FromStr for Flavor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ns" => Ok(Flavor::Ns),
+            "microdesc" => Ok(Flavor::Microdesc),
+            _ => Err(format!("Unknown flavor: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Consensus {
+    pub(crate) flavor: Flavor,
+    /// The version of the algorithm the directory authorities produced the consensus with.
+    pub(crate) consensus_method: u32,
     pub(crate) valid_after: DateTime<Utc>,
+    /// Until when the consensus is the newest one. After this, a newer consensus should be
+    /// downloaded.
+    pub(crate) fresh_until: DateTime<Utc>,
     pub(crate) valid_until: DateTime<Utc>,
+    /// The shared random value of the current period, if the authorities agreed on one.
+    pub(crate) shared_random_value: Option<Vec<u8>>,
+    /// The Tor versions recommended for clients, e.g. `0.4.7.13`.
+    pub(crate) client_versions: Vec<String>,
+    /// The Tor versions recommended for relays.
+    pub(crate) server_versions: Vec<String>,
+    /// The headers of the bandwidth file the bandwidths were measured in, e.g. `timestamp`.
+    pub(crate) bandwidth_file_headers: Option<HashMap<String, String>>,
+    /// The digests of the bandwidth file, keyed by algorithm, e.g. `sha256`.
+    pub(crate) bandwidth_file_digest: Option<HashMap<String, String>>,
+    /// The network parameters in the "params" line, e.g. `bwweightscale`.
+    pub(crate) params: HashMap<String, i32>,
+    /// The weights onion routers' bandwidths are multiplied by per position in a path, e.g.
+    /// `Wgd`. See `consensus_weight`.
+    pub(crate) bandwidth_weights: Option<HashMap<String, i32>>,
+    /// The fingerprints of the directory authorities with a "directory-signature" line.
+    signing_authorities: Vec<String>,
+    /// The lines preceding the first "r" line, as they are in the document.
+    header: Vec<String>,
+    /// The lines from "directory-footer" on, as they are in the document. Empty if the document
+    /// has no footer.
+    footer: Vec<String>,
+    /// Whether the entries of some onion routers were skipped, e.g. past `ONION_ROUTER_LIMIT`.
+    truncated: bool,
+    /// The lines with a keyword that isn't parsed, along with their 1-based line numbers.
+    /// Only collected with `ParseOptions::collect_unknown`.
+    pub(crate) unknown_lines: Vec<(usize, String)>,
     pub(crate) onion_routers: Vec<OnionRouter>,
 }
 
-#[derive(Debug)]
-pub(crate) struct OnionRouter {
-    nickname: String,
-    ip: Ipv4Addr,
-    or_port: u16,
-    dir_port: u16,
-    flags: Flags,
-}
+impl Consensus {
+    /// Whether `now` is within the validity window of the consensus.
+    /// `skew_tolerance` widens the window on both sides to allow for clock skew.
+    pub fn is_valid_at(&self, now: &DateTime<Utc>, skew_tolerance: Duration) -> bool {
+        self.check_validity_at(now, skew_tolerance).is_ok()
+    }
 
-impl OnionRouter {
-    fn is_stable(&self) -> bool {
-        for f in [Flags::STABLE, Flags::FAST, Flags::VALID, Flags::RUNNING] {
-            if !self.flags.contains(f) {
-                return false;
+    /// Same as `is_valid_at`, telling which side of the validity window `now` is on if it's
+    /// outside.
+    pub fn check_validity_at(
+        &self,
+        now: &DateTime<Utc>,
+        skew_tolerance: Duration,
+    ) -> Result<(), ValidityError> {
+        if *now < self.valid_after - skew_tolerance {
+            return Err(ValidityError::NotYetValid(self.valid_after));
+        }
+        if *now > self.valid_until + skew_tolerance {
+            return Err(ValidityError::Expired(self.valid_until));
+        }
+
+        Ok(())
+    }
+
+    /// Whether the consensus recommends `version`, e.g. `0.4.7.13`, for clients.
+    pub fn is_client_version_recommended(&self, version: &str) -> bool {
+        self.client_versions.iter().any(|v| v == version)
+    }
+
+    /// Whether the consensus recommends `version`, e.g. `0.4.7.13`, for relays.
+    pub fn is_server_version_recommended(&self, version: &str) -> bool {
+        self.server_versions.iter().any(|v| v == version)
+    }
+
+    /// The version of the algorithm the directory authorities produced the consensus with.
+    pub fn consensus_method(&self) -> u32 {
+        self.consensus_method
+    }
+
+    /// The headers of the bandwidth file the bandwidths were measured in, e.g. `timestamp`, if
+    /// the consensus has a "bandwidth-file-headers" line.
+    pub fn bandwidth_file_headers(&self) -> Option<&HashMap<String, String>> {
+        self.bandwidth_file_headers.as_ref()
+    }
+
+    /// The digests of the bandwidth file keyed by algorithm, e.g. `sha256`, if the consensus has
+    /// a "bandwidth-file-digest" line.
+    pub fn bandwidth_file_digest(&self) -> Option<&HashMap<String, String>> {
+        self.bandwidth_file_digest.as_ref()
+    }
+
+    /// The lines with a keyword that isn't parsed, along with their 1-based line numbers. Empty
+    /// unless parsed with `ParseOptions::collect_unknown`.
+    pub fn unknown_lines(&self) -> &[(usize, String)] {
+        &self.unknown_lines
+    }
+
+    /// valid-after as seconds since the Unix epoch.
+    pub fn valid_after_epoch(&self) -> i64 {
+        self.valid_after.timestamp()
+    }
+
+    /// valid-until as seconds since the Unix epoch.
+    pub fn valid_until_epoch(&self) -> i64 {
+        self.valid_until.timestamp()
+    }
+
+    /// The identity fingerprints of the directory authorities that signed the consensus, e.g.
+    /// `0232AF901C31A04EE9848595AF9BB7620D4C5B2E`. The signatures themselves aren't verified.
+    pub fn signing_authorities(&self) -> Vec<String> {
+        self.signing_authorities.clone()
+    }
+
+    /// How long a guard is used before another one is chosen.
+    //
+    // https://github.com/torproject/torspec/blob/main/guard-spec.txt
+    // 4.1. Guard selection
+    //    {GUARD_LIFETIME} -- 120 days (consensus parameter "guard-lifetime-days")
+    pub fn guard_lifetime(&self) -> Duration {
+        let days = self
+            .params
+            .get("guard-lifetime-days")
+            .map_or(DEFAULT_GUARD_LIFETIME_DAYS, |days| i64::from(*days));
+        Duration::days(days)
+    }
+
+    /// Whether a guard chosen at `chosen_at` has reached the end of its lifetime at `now`, see
+    /// `guard_lifetime`.
+    pub fn should_rotate_guard(&self, chosen_at: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        now >= chosen_at + self.guard_lifetime()
+    }
+
+    /// Whether the onion routers are only some of those in the document because the rest were
+    /// skipped, in which case `relay_count` understates the size of the network.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// The number of onion routers in the consensus.
+    pub fn relay_count(&self) -> usize {
+        self.onion_routers.len()
+    }
+
+    /// Keeps only the onion routers for which `f` returns true, e.g. to write a subset of the
+    /// consensus out with `write_document`.
+    pub fn retain_relays<F: FnMut(&OnionRouter) -> bool>(&mut self, f: F) {
+        self.onion_routers.retain(f);
+    }
+
+    /// The onion routers in the order of the document.
+    pub fn onion_routers(&self) -> &[OnionRouter] {
+        &self.onion_routers
+    }
+
+    /// Checks that every onion router has an identity. An empty one means that an "r" line was
+    /// misparsed, so this can be called after parsing to catch such bugs.
+    pub fn check_identities(&self) -> Result<(), ParseError> {
+        match self.onion_routers.iter().find(|or| or.identity.is_empty()) {
+            Some(or) => Err(ParseError::EmptyIdentity(or.nickname.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// The onion routers that have all the `flags`, e.g. `Flags::GUARD | Flags::FAST`, in the
+    /// order of the document.
+    pub fn relays_with(&self, flags: Flags) -> impl Iterator<Item = &OnionRouter> {
+        self.onion_routers
+            .iter()
+            .filter(move |or| or.flags.contains(flags))
+    }
+
+    /// The first onion router named `nickname`, compared case-insensitively as Tor does.
+    /// Nicknames aren't unique, so `find_by_identity` should be preferred where the identity is
+    /// known.
+    pub fn find_by_nickname(&self, nickname: &str) -> Option<&OnionRouter> {
+        self.onion_routers
+            .iter()
+            .find(|or| or.nickname.eq_ignore_ascii_case(nickname))
+    }
+
+    /// The onion router with the identity, e.g. as decoded from its fingerprint.
+    pub fn find_by_identity(&self, identity: &[u8]) -> Option<&OnionRouter> {
+        self.onion_routers.iter().find(|or| or.identity == identity)
+    }
+
+    /// The onion routers sorted by `by`, in ascending order. Onion routers that are equal by `by`
+    /// are ordered by identity, so that the order is the same across runs, e.g. to diff reports.
+    pub fn relays_sorted(&self, by: SortKey) -> Vec<&OnionRouter> {
+        let mut relays = self.onion_routers.iter().collect::<Vec<_>>();
+        relays.sort_by(|a, b| {
+            match by {
+                SortKey::Identity => Ordering::Equal,
+                SortKey::Nickname => a.nickname.cmp(&b.nickname),
+                SortKey::Bandwidth => a.bandwidth.cmp(&b.bandwidth),
+                SortKey::Ip => a.ip.cmp(&b.ip),
             }
+            .then_with(|| a.identity.cmp(&b.identity))
+        });
+        relays
+    }
+
+    /// Returns up to `limit` onion routers starting at `offset`.
+    /// An offset past the end yields an empty slice.
+    pub fn page(&self, offset: usize, limit: usize) -> &[OnionRouter] {
+        let start = offset.min(self.onion_routers.len());
+        let end = start.saturating_add(limit).min(self.onion_routers.len());
+        &self.onion_routers[start..end]
+    }
+
+    /// Counts, for each of the ports, the exit relays whose exit policy allows it.
+    pub fn exit_port_coverage(&self, ports: &[u16]) -> HashMap<u16, usize> {
+        let exits = self
+            .onion_routers
+            .iter()
+            .filter(|or| or.flags.contains(Flags::EXIT) && !or.flags.contains(Flags::BAD_EXIT))
+            .collect::<Vec<_>>();
+
+        ports
+            .iter()
+            .map(|port| {
+                let count = exits.iter().filter(|or| or.allows_exit_port(*port)).count();
+                (*port, count)
+            })
+            .collect()
+    }
+
+    /// The onion routers with an IPv4 address in an "a" line that differs from the one in their
+    /// "r" line, which may indicate a misconfiguration.
+    pub fn relays_with_mismatched_ipv4(&self) -> Vec<&OnionRouter> {
+        self.onion_routers
+            .iter()
+            .filter(|or| {
+                or.or_addresses[1..].iter().any(|address| match address {
+                    SocketAddr::V4(address) => *address.ip() != or.ip,
+                    SocketAddr::V6(_) => false,
+                })
+            })
+            .collect()
+    }
+
+    /// The onion routers with both the Guard and the Exit flags. They are rare, and are weighted
+    /// differently in path selection (Wgd, Wed, etc.).
+    pub fn guard_and_exit_relays(&self) -> Vec<&OnionRouter> {
+        self.onion_routers
+            .iter()
+            .filter(|or| or.flags.contains(Flags::GUARD | Flags::EXIT))
+            .collect()
+    }
+
+    /// How the onion routers in `newer` differ from those in this consensus, matched by
+    /// identity.
+    pub fn diff(&self, newer: &Consensus) -> ConsensusDiff {
+        let flags = self
+            .onion_routers
+            .iter()
+            .map(|or| (&or.identity, or.flags))
+            .collect::<HashMap<_, _>>();
+        let newer_flags = newer
+            .onion_routers
+            .iter()
+            .map(|or| (&or.identity, or.flags))
+            .collect::<HashMap<_, _>>();
+
+        ConsensusDiff {
+            added: newer_flags
+                .keys()
+                .filter(|identity| !flags.contains_key(*identity))
+                .count(),
+            removed: flags
+                .keys()
+                .filter(|identity| !newer_flags.contains_key(*identity))
+                .count(),
+            flags_changed: newer_flags
+                .iter()
+                .filter(|(identity, f)| flags.get(*identity).is_some_and(|old| old != *f))
+                .count(),
+        }
+    }
+
+    /// Counts the onion routers per exact set of flags, e.g. how many have exactly
+    /// Fast, Guard, Running, Stable and Valid.
+    pub fn flag_combinations(&self) -> HashMap<Flags, usize> {
+        let mut combinations = HashMap::new();
+        for or in &self.onion_routers {
+            *combinations.entry(or.flags).or_insert(0) += 1;
         }
 
-        true
+        combinations
     }
-}
 
-bitflags! {
-    pub(crate) struct Flags: u32 {
-        const AUTHORITY = 0b0000000000001;
-        const BAD_EXIT = 0b0000000000010;
-        const EXIT = 0b0000000000100;
-        const FAST = 0b0000000001000;
-        const GUARD = 0b0000000010000;
-        const HS_DIR = 0b0000000100000;
-        const MIDDLE_ONLY = 0b0000001000000;
-        const NO_ED_CONSENSUS = 0b0000010000000;
-        const STABLE = 0b0000100000000;
-        const STALE_DESC = 0b0001000000000;
-        const RUNNING = 0b0010000000000;
-        const VALID = 0b0100000000000;
-        const V2DIR = 0b1000000000000;
+    /// Summarizes the consensus for a network health report.
+    pub fn network_stats(&self) -> NetworkStats {
+        let count = |flags| {
+            self.onion_routers
+                .iter()
+                .filter(|or| or.flags.contains(flags))
+                .count()
+        };
+        let mut bandwidths = self
+            .onion_routers
+            .iter()
+            .map(|or| or.bandwidth.unwrap_or(0))
+            .collect::<Vec<_>>();
+        bandwidths.sort_unstable();
+
+        NetworkStats {
+            flavor: self.flavor.name(),
+            valid_after: self.valid_after_epoch(),
+            valid_until: self.valid_until_epoch(),
+            relay_count: self.relay_count(),
+            guard_count: count(Flags::GUARD),
+            exit_count: count(Flags::EXIT),
+            hsdir_count: count(Flags::HS_DIR),
+            total_bandwidth: bandwidths.iter().map(|b| u64::from(*b)).sum(),
+            // The lower median if the number of onion routers is even.
+            median_bandwidth: bandwidths
+                .get(bandwidths.len().saturating_sub(1) / 2)
+                .copied()
+                .unwrap_or(0),
+        }
     }
-}
 
-impl From<&str> for Flags {
-    fn from(s: &str) -> Self {
-        match s {
-            "Authority" => Flags::AUTHORITY,
-            "BadExit" => Flags::BAD_EXIT,
-            "Exit" => Flags::EXIT,
-            "Fast" => Flags::FAST,
-            "Guard" => Flags::GUARD,
-            "HSDir" => Flags::HS_DIR,
-            "MiddleOnly" => Flags::MIDDLE_ONLY,
-            "NoEdConsensus" => Flags::NO_ED_CONSENSUS,
-            "Stable" => Flags::STABLE,
-            "StaleDesc" => Flags::STALE_DESC,
-            "Running" => Flags::RUNNING,
-            "Valid" => Flags::VALID,
-            "V2Dir" => Flags::V2DIR,
-            _ => unreachable!(),
+    /// Writes the onion routers in the format of Tor's `fingerprint` file, one
+    /// `nickname fingerprint` line per onion router.
+    pub fn write_fingerprints<W: Write>(&self, mut w: W) -> std::io::Result<()> {
+        for or in &self.onion_routers {
+            writeln!(w, "{} {}", or.nickname, or.spaced_fingerprint())?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the validity window (as Unix timestamps) and the onion routers as a JSON object.
+    pub fn write_json<W: Write>(&self, mut w: W) -> std::io::Result<()> {
+        writeln!(w, "{{")?;
+        writeln!(w, "  \"flavor\": \"{}\",", self.flavor.name())?;
+        writeln!(w, "  \"valid_after\": {},", self.valid_after_epoch())?;
+        writeln!(w, "  \"fresh_until\": {},", self.fresh_until.timestamp())?;
+        writeln!(w, "  \"valid_until\": {},", self.valid_until_epoch())?;
+        // Nicknames and flag names are alphanumeric, so nothing needs escaping.
+        let onion_routers = self
+            .onion_routers
+            .iter()
+            .map(|or| {
+                format!(
+                    "    {{\"nickname\": \"{}\", \"fingerprint\": \"{}\", \"or_addr\": \"{}\", \"flags\": \"{}\"}}",
+                    or.nickname,
+                    or.fingerprint(),
+                    or.or_socket_addr(),
+                    or.flags.to_comma_list()
+                )
+            })
+            .collect::<Vec<_>>();
+        writeln!(w, "  \"onion_routers\": [")?;
+        writeln!(w, "{}", onion_routers.join(",\n"))?;
+        writeln!(w, "  ]")?;
+        writeln!(w, "}}")
+    }
+
+    /// Writes the consensus back out in the dir-spec format: the header and the footer as they
+    /// were parsed, with an entry for each of the onion routers in between.
+    /// Filtering the onion routers beforehand (see `retain_relays`) yields a smaller but otherwise
+    /// valid document.
+    pub fn write_document<W: Write>(&self, mut w: W) -> std::io::Result<()> {
+        for line in &self.header {
+            writeln!(w, "{}", line)?;
         }
+        for or in &self.onion_routers {
+            or.write_entry(&mut w)?;
+        }
+        for line in &self.footer {
+            writeln!(w, "{}", line)?;
+        }
+
+        Ok(())
+    }
+
+    /// Chooses a guard relay with a probability proportional to its consensus weight.
+    /// See `choose_by_weight` for onion routers without bandwidth.
+    pub fn choose_guard_relay(&self, options: &SelectionOptions) -> Result<&OnionRouter, String> {
+        self.choose_guard_relay_with_weight(options)
+            .map(|(or, _)| or)
+    }
+
+    /// Same as `choose_guard_relay`, also returning the consensus weight the guard relay was
+    /// chosen with, e.g. to log why it was chosen.
+    pub fn choose_guard_relay_with_weight(
+        &self,
+        options: &SelectionOptions,
+    ) -> Result<(&OnionRouter, u64), String> {
+        self.choose_and_weigh(
+            &self.candidates(Flags::GUARD, options),
+            Position::Guard,
+            &mut rand::thread_rng(),
+        )
+        .ok_or_else(|| "No guard relay available".to_string())
+    }
+
+    /// Chooses a guard relay that supports `min_version` or a later version of the Link protocol,
+    /// e.g. 4 for Ed25519 link authentication.
+    pub fn choose_guard_relay_with_link_protocol(
+        &self,
+        min_version: u32,
+        options: &SelectionOptions,
+    ) -> Result<&OnionRouter, String> {
+        let candidates = self
+            .candidates(Flags::GUARD, options)
+            .into_iter()
+            .filter(|or| or.protocols.supports_at_least("Link", min_version))
+            .collect::<Vec<_>>();
+        self.choose_by_weight(&candidates, Position::Guard)
+            .ok_or_else(|| "No guard relay available".to_string())
+    }
+
+    /// Chooses a directory relay (not an authority) to fetch documents such as microdescriptors
+    /// from, which takes load off the directory authorities.
+    pub fn choose_directory_relay(
+        &self,
+        options: &SelectionOptions,
+    ) -> Result<&OnionRouter, String> {
+        self.candidates(Flags::V2DIR | Flags::RUNNING, options)
+            .into_iter()
+            .filter(|or| or.dir_port != 0)
+            .collect::<Vec<_>>()
+            .choose(&mut rand::thread_rng())
+            .copied()
+            .ok_or_else(|| "No directory relay available".to_string())
+    }
+
+    /// Chooses an exit relay whose exit policy allows `port`, with a probability proportional to
+    /// its consensus weight. Onion routers flagged as bad exits are never chosen, unless
+    /// `options.avoid_flags` is set to other flags.
+    pub fn choose_exit_relay(
+        &self,
+        port: u16,
+        options: &SelectionOptions,
+    ) -> Result<&OnionRouter, String> {
+        self.choose_exit_relay_with_weight(port, options)
+            .map(|(or, _)| or)
+    }
+
+    /// Same as `choose_exit_relay`, also returning the consensus weight the exit relay was chosen
+    /// with.
+    pub fn choose_exit_relay_with_weight(
+        &self,
+        port: u16,
+        options: &SelectionOptions,
+    ) -> Result<(&OnionRouter, u64), String> {
+        let candidates = self
+            .candidates(Flags::EXIT, options)
+            .into_iter()
+            .filter(|or| {
+                !or.flags
+                    .intersects(options.avoid_flags.unwrap_or(Flags::BAD_EXIT))
+            })
+            .filter(|or| or.allows_exit_port(port))
+            .collect::<Vec<_>>();
+        self.choose_and_weigh(&candidates, Position::Exit, &mut rand::thread_rng())
+            .ok_or_else(|| format!("No exit relay available for port {}", port))
+    }
+
+    /// Chooses a middle relay that isn't one of the `chosen` onion routers, e.g. the guard and
+    /// exit relays of the path, with a probability proportional to its consensus weight. Onion
+    /// routers flagged as middle-only are eligible, since the middle is the one position they may
+    /// take.
+    pub fn choose_middle_relay(
+        &self,
+        chosen: &[&OnionRouter],
+        options: &SelectionOptions,
+    ) -> Result<&OnionRouter, String> {
+        self.choose_middle_relay_with_weight(chosen, options)
+            .map(|(or, _)| or)
+    }
+
+    /// Same as `choose_middle_relay`, also returning the consensus weight the middle relay was
+    /// chosen with.
+    pub fn choose_middle_relay_with_weight(
+        &self,
+        chosen: &[&OnionRouter],
+        options: &SelectionOptions,
+    ) -> Result<(&OnionRouter, u64), String> {
+        let candidates = self
+            .candidates(Flags::RUNNING | Flags::VALID | Flags::FAST, options)
+            .into_iter()
+            .filter(|or| chosen.iter().all(|c| c.identity != or.identity))
+            .collect::<Vec<_>>();
+        self.choose_and_weigh(&candidates, Position::Middle, &mut rand::thread_rng())
+            .ok_or_else(|| "No middle relay available".to_string())
+    }
+
+    /// The weight of the onion router for being chosen for the position: its bandwidth
+    /// multiplied by the bandwidth weight for the position and its flags, e.g. `Wgd` for a guard
+    /// relay that is also an exit relay chosen as a guard. Without bandwidth weights in the
+    /// consensus, the weight is the bandwidth as is.
+    //
+    // https://github.com/torproject/torspec/blob/main/dir-spec.txt
+    // 3.8.3. Computing Bandwidth Weights
+    //    Wgg, Wgm, Wgd: weights for Guard, non-flagged and Guard+Exit nodes in the guard position
+    //    Wmg, Wmm, Wme, Wmd: weights for the middle position
+    //    Weg, Wem, Wee, Wed: weights for the exit position
+    //    ...
+    //    "bwweightscale" -- Value that bandwidth-weights are divided by. Default 10000.
+    pub fn consensus_weight(&self, or: &OnionRouter, position: Position) -> u64 {
+        let bandwidth = u64::from(or.bandwidth.unwrap_or(0));
+        let weights = match &self.bandwidth_weights {
+            Some(weights) => weights,
+            None => return bandwidth,
+        };
+
+        let is_guard = or.flags.contains(Flags::GUARD);
+        let is_exit = or.flags.contains(Flags::EXIT) && !or.flags.contains(Flags::BAD_EXIT);
+        let key = match (position, is_guard, is_exit) {
+            (Position::Guard, true, true) => "Wgd",
+            (Position::Guard, true, false) => "Wgg",
+            // There is no weight for exit relays in the guard position, they aren't guards.
+            (Position::Guard, false, true) => return 0,
+            (Position::Guard, false, false) => "Wgm",
+            (Position::Middle, true, true) => "Wmd",
+            (Position::Middle, true, false) => "Wmg",
+            (Position::Middle, false, true) => "Wme",
+            (Position::Middle, false, false) => "Wmm",
+            (Position::Exit, true, true) => "Wed",
+            (Position::Exit, true, false) => "Weg",
+            (Position::Exit, false, true) => "Wee",
+            (Position::Exit, false, false) => "Wem",
+        };
+        let scale = self
+            .params
+            .get("bwweightscale")
+            .map_or(DEFAULT_BW_WEIGHT_SCALE, |scale| i64::from(*scale))
+            .max(1);
+        let weight = weights
+            .get(key)
+            .map_or(scale, |weight| i64::from(*weight))
+            .max(0);
+
+        bandwidth * weight as u64 / scale as u64
+    }
+
+    /// The shares of the consensus-weighted bandwidth (see `consensus_weight`) available for the
+    /// guard, middle and exit positions, in that order. The guard position counts the relays
+    /// with the Guard flag, the exit position those with the Exit flag but not BadExit, and the
+    /// middle position all of them. The shares add up to 1, or are all 0 without any bandwidth.
+    pub fn position_bandwidth_fraction(&self) -> (f64, f64, f64) {
+        let weighted = |flags: Flags, position: Position| -> u64 {
+            self.onion_routers
+                .iter()
+                .filter(|or| or.flags.contains(flags))
+                .filter(|or| position != Position::Exit || !or.flags.contains(Flags::BAD_EXIT))
+                .map(|or| self.consensus_weight(or, position))
+                .sum()
+        };
+        let guard = weighted(Flags::GUARD, Position::Guard);
+        let middle = weighted(Flags::empty(), Position::Middle);
+        let exit = weighted(Flags::EXIT, Position::Exit);
+
+        let total = guard + middle + exit;
+        if total == 0 {
+            return (0.0, 0.0, 0.0);
+        }
+        let total = total as f64;
+        (
+            guard as f64 / total,
+            middle as f64 / total,
+            exit as f64 / total,
+        )
+    }
+
+    /// Chooses one of the candidates with a probability proportional to its consensus weight for
+    /// the position (see `consensus_weight`). Onion routers without a "w" line count as having
+    /// no bandwidth. If none of the candidates has any weight, e.g. in a hand-written document,
+    /// they are chosen uniformly instead.
+    pub fn choose_by_weight<'a>(
+        &self,
+        candidates: &[&'a OnionRouter],
+        position: Position,
+    ) -> Option<&'a OnionRouter> {
+        self.choose_by_weight_with_rng(candidates, position, &mut rand::thread_rng())
+    }
+
+    /// Same as `choose_by_weight`, with the given source of randomness.
+    pub fn choose_by_weight_with_rng<'a, R: Rng + ?Sized>(
+        &self,
+        candidates: &[&'a OnionRouter],
+        position: Position,
+        rng: &mut R,
+    ) -> Option<&'a OnionRouter> {
+        self.choose_and_weigh(candidates, position, rng)
+            .map(|(or, _)| or)
+    }
+
+    /// Chooses one of the onion routers that have all the `required` flags and none of the
+    /// `forbidden` ones, with a probability proportional to `score`, e.g. to prefer low latency.
+    /// Negative and non-finite scores count as 0. If all the scores are 0, the onion routers are
+    /// chosen uniformly instead.
+    pub fn choose_weighted_by<F: Fn(&OnionRouter) -> f64>(
+        &self,
+        required: Flags,
+        forbidden: Flags,
+        score: F,
+    ) -> Option<&OnionRouter> {
+        self.choose_weighted_by_with_rng(required, forbidden, score, &mut rand::thread_rng())
+    }
+
+    /// Same as `choose_weighted_by`, with the given source of randomness.
+    pub fn choose_weighted_by_with_rng<F: Fn(&OnionRouter) -> f64, R: Rng + ?Sized>(
+        &self,
+        required: Flags,
+        forbidden: Flags,
+        score: F,
+        rng: &mut R,
+    ) -> Option<&OnionRouter> {
+        let candidates = self
+            .relays_with(required)
+            .filter(|or| !or.flags.intersects(forbidden))
+            .collect::<Vec<_>>();
+        let scores = candidates.iter().map(|or| {
+            let score = score(or);
+            if score.is_finite() && score > 0.0 {
+                score
+            } else {
+                0.0
+            }
+        });
+        let i = match WeightedIndex::new(scores) {
+            Ok(index) => index.sample(rng),
+            Err(_) if candidates.is_empty() => return None,
+            // All the scores are zero.
+            Err(_) => rng.gen_range(0..candidates.len()),
+        };
+
+        Some(candidates[i])
+    }
+
+    // Same as `choose_by_weight_with_rng`, also returning the consensus weight of the chosen onion
+    // router.
+    fn choose_and_weigh<'a, R: Rng + ?Sized>(
+        &self,
+        candidates: &[&'a OnionRouter],
+        position: Position,
+        rng: &mut R,
+    ) -> Option<(&'a OnionRouter, u64)> {
+        let weights = candidates
+            .iter()
+            .map(|or| self.consensus_weight(or, position))
+            .collect::<Vec<_>>();
+        let i = match WeightedIndex::new(weights.iter().copied()) {
+            Ok(index) => index.sample(rng),
+            Err(_) if candidates.is_empty() => return None,
+            // All the weights are zero.
+            Err(_) => rng.gen_range(0..candidates.len()),
+        };
+
+        Some((candidates[i], weights[i]))
+    }
+
+    /// The onion routers that have all the `flags` and aren't ruled out by the options.
+    pub fn candidates(&self, flags: Flags, options: &SelectionOptions) -> Vec<&OnionRouter> {
+        let candidates = self
+            .onion_routers
+            .iter()
+            .filter(|or| or.flags.contains(flags))
+            .filter(|or| options.allow_invalid || or.flags.contains(Flags::VALID))
+            .filter(|or| !options.measured_only || or.is_bandwidth_measured())
+            .filter(|or| !options.exclude.contains(&or.fingerprint()))
+            .filter(|or| {
+                !or.flags
+                    .intersects(options.avoid_flags.unwrap_or(Flags::empty()))
+            })
+            .filter(|or| {
+                !options
+                    .excluded_subnets
+                    .iter()
+                    .any(|subnet| subnet.contains(&or.ip))
+            })
+            .collect::<Vec<_>>();
+
+        if options.min_bandwidth_percentile <= 0.0 || candidates.is_empty() {
+            return candidates;
+        }
+        // The nearest-rank percentile of the bandwidths of the candidates.
+        let mut bandwidths = candidates
+            .iter()
+            .map(|or| or.bandwidth.unwrap_or(0))
+            .collect::<Vec<_>>();
+        bandwidths.sort_unstable();
+        let rank = (options.min_bandwidth_percentile.min(1.0) * bandwidths.len() as f64).ceil();
+        let threshold = bandwidths[(rank as usize).max(1) - 1];
+
+        candidates
+            .into_iter()
+            .filter(|or| or.bandwidth.unwrap_or(0) >= threshold)
+            .collect()
+    }
+}
+
+/// The numbers of onion routers that changed between two consensuses, see `Consensus::diff`.
+#[derive(Debug, Default, PartialEq)]
+pub struct ConsensusDiff {
+    pub added: usize,
+    pub removed: usize,
+    /// Onion routers in both whose flags differ.
+    pub flags_changed: usize,
+}
+
+impl fmt::Display for ConsensusDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} removed, {} reflagged",
+            self.added, self.removed, self.flags_changed
+        )
+    }
+}
+
+/// Aggregate statistics of a consensus, see `Consensus::network_stats`.
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct NetworkStats {
+    pub flavor: &'static str,
+    /// valid-after as seconds since the Unix epoch.
+    pub valid_after: i64,
+    /// valid-until as seconds since the Unix epoch.
+    pub valid_until: i64,
+    pub relay_count: usize,
+    pub guard_count: usize,
+    pub exit_count: usize,
+    pub hsdir_count: usize,
+    /// The sum of the bandwidths in kilobytes per second. Onion routers without a "w" line count
+    /// as 0.
+    pub total_bandwidth: u64,
+    pub median_bandwidth: u32,
+}
+
+/// Options for the relay selectors.
+#[derive(Debug, Default)]
+pub struct SelectionOptions {
+    /// Exclude onion routers whose bandwidth wasn't measured by the bandwidth authorities.
+    pub measured_only: bool,
+    /// The fingerprints (see `OnionRouter::fingerprint`) of onion routers not to select, e.g. the
+    /// ones used by recent circuits.
+    pub exclude: HashSet<String>,
+    /// Exclude onion routers with any of these flags. `None` excludes BAD_EXIT relays from the
+    /// exit position and nothing from the others.
+    pub avoid_flags: Option<Flags>,
+    /// Include onion routers without the Valid flag.
+    pub allow_invalid: bool,
+    /// Exclude the onion routers whose bandwidth is below this percentile, between 0 and 1, of
+    /// the bandwidths of the otherwise eligible ones. E.g. 0.5 excludes those below the median.
+    pub min_bandwidth_percentile: f64,
+    /// Exclude onion routers whose IPv4 address is in any of these networks, e.g. a hosting
+    /// provider's `203.0.113.0/24`.
+    pub excluded_subnets: Vec<Ipv4Net>,
+}
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OnionRouter {
+    nickname: String,
+    /// The hash of the onion router's identity key.
+    identity: Vec<u8>,
+    /// The hash of the onion router's most recent descriptor. Only in the ns flavor.
+    digest: Option<Vec<u8>>,
+    /// The publication time of the onion router's most recent descriptor.
+    publication: DateTime<Utc>,
+    ip: Ipv4Addr,
+    or_port: u16,
+    /// The addresses of the OR port: the one in the "r" line, followed by those in "a" lines.
+    or_addresses: Vec<SocketAddr>,
+    dir_port: u16,
+    flags: Flags,
+    exit_policy: Option<ExitPolicy>,
+    protocols: Protocols,
+    /// The version of Tor the onion router runs, from the "v" line, e.g. `Tor 0.4.7.13`.
+    version: Option<String>,
+    /// The bandwidth in kilobytes per second, from the "w" line.
+    bandwidth: Option<u32>,
+    /// The bandwidth measured by the bandwidth authorities, from the "w" line. Only in votes.
+    measured: Option<u32>,
+    /// Whether the "w" line has "Unmeasured=1".
+    unmeasured: bool,
+    /// The base64-encoded SHA256 digest of the onion router's microdescriptor, from the "m" line.
+    microdesc_digest: Option<String>,
+    /// The Ed25519 identity key. Not in the consensus, it is taken from the onion router's
+    /// microdescriptor.
+    ed25519_identity: Option<Vec<u8>>,
+    /// The onion routers the onion router declares to be in its family, e.g.
+    /// `$9695DFC35FFEB861329B9F1AB04C46397020CE31`. Not in the consensus, it is taken from the
+    /// onion router's microdescriptor.
+    family: Vec<String>,
+}
+
+impl OnionRouter {
+    // Writes the "r" line and the lines following it.
+    fn write_entry<W: Write>(&self, mut w: W) -> std::io::Result<()> {
+        write!(
+            w,
+            "r {} {}",
+            self.nickname,
+            base64::encode_config(&self.identity, base64::STANDARD_NO_PAD)
+        )?;
+        if let Some(digest) = &self.digest {
+            write!(
+                w,
+                " {}",
+                base64::encode_config(digest, base64::STANDARD_NO_PAD)
+            )?;
+        }
+        writeln!(
+            w,
+            " {} {} {} {}",
+            self.publication.format("%Y-%m-%d %H:%M:%S"),
+            self.ip,
+            self.or_port,
+            self.dir_port
+        )?;
+        // The first address is the one in the "r" line.
+        for address in &self.or_addresses[1..] {
+            writeln!(w, "a {}", address)?;
+        }
+        if let Some(digest) = &self.microdesc_digest {
+            writeln!(w, "m {}", digest)?;
+        }
+        writeln!(w, "s {}", self.flags)?;
+        if let Some(version) = &self.version {
+            writeln!(w, "v {}", version)?;
+        }
+        if !self.protocols.is_empty() {
+            writeln!(w, "pr {}", self.protocols)?;
+        }
+        if let Some(bandwidth) = self.bandwidth {
+            write!(w, "w Bandwidth={}", bandwidth)?;
+            if let Some(measured) = self.measured {
+                write!(w, " Measured={}", measured)?;
+            }
+            if self.unmeasured {
+                write!(w, " Unmeasured=1")?;
+            }
+            writeln!(w)?;
+        }
+        if let Some(exit_policy) = &self.exit_policy {
+            writeln!(w, "p {}", exit_policy)?;
+        }
+
+        Ok(())
+    }
+
+    /// The identity as upper-case hex, e.g. `9695DFC35FFEB861329B9F1AB04C46397020CE31`.
+    pub fn fingerprint(&self) -> String {
+        self.identity.iter().map(|b| format!("{:02X}", b)).collect()
+    }
+
+    // The fingerprint split into groups of four characters, as Tor writes it in the `fingerprint`
+    // file, e.g. `9695 DFC3 5FFE B861 329B 9F1A B04C 4639 7020 CE31`.
+    fn spaced_fingerprint(&self) -> String {
+        self.fingerprint()
+            .as_bytes()
+            .chunks(4)
+            .map(|chunk| String::from_utf8_lossy(chunk))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    // Onion routers without the Valid flag are kept, and excluded at selection time instead.
+    // See `SelectionOptions::allow_invalid`.
+    fn is_stable(&self) -> bool {
+        for f in [Flags::STABLE, Flags::FAST, Flags::RUNNING] {
+            if !self.flags.contains(f) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Checks that the flags of the onion router don't contradict each other.
+    // https://github.com/torproject/torspec/blob/main/dir-spec.txt
+    // 3.4.2. Assigning flags in a vote
+    pub fn validate_flags(&self) -> Result<(), FlagError> {
+        let positional = self.flags & (Flags::GUARD | Flags::EXIT | Flags::HS_DIR);
+        if positional.is_empty() {
+            return Ok(());
+        }
+
+        // Authorities only assign these flags to onion routers they consider running.
+        if !self.flags.contains(Flags::RUNNING) {
+            return Err(FlagError::NotRunning(positional));
+        }
+
+        // A MiddleOnly onion router has its Guard, Exit and HSDir flags removed.
+        if self.flags.contains(Flags::MIDDLE_ONLY) {
+            return Err(FlagError::Conflicting(Flags::MIDDLE_ONLY, positional));
+        }
+
+        Ok(())
+    }
+
+    pub fn nickname(&self) -> &str {
+        &self.nickname
+    }
+
+    pub fn identity(&self) -> &[u8] {
+        &self.identity
+    }
+
+    /// The hash of the onion router's most recent descriptor. Only in the ns flavor.
+    pub fn digest(&self) -> Option<&[u8]> {
+        self.digest.as_deref()
+    }
+
+    /// The publication time of the onion router's most recent descriptor.
+    pub fn publication(&self) -> DateTime<Utc> {
+        self.publication
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    /// The flags as an integer for storage, which `Flags::from_bits` turns back into the flags.
+    /// See `Flags` for the bits.
+    pub fn flags_bits(&self) -> u32 {
+        self.flags.bits()
+    }
+
+    pub fn ed25519_identity(&self) -> Option<&[u8]> {
+        self.ed25519_identity.as_deref()
+    }
+
+    /// Sets the Ed25519 identity key taken from the onion router's microdescriptor.
+    pub fn set_ed25519_identity(&mut self, key: Vec<u8>) {
+        self.ed25519_identity = Some(key);
+    }
+
+    pub fn family(&self) -> &[String] {
+        &self.family
+    }
+
+    /// Sets the family taken from the onion router's microdescriptor.
+    pub fn set_family(&mut self, family: Vec<String>) {
+        self.family = family;
+    }
+
+    /// Whether the two onion routers are in the same family, i.e. each one lists the other in
+    /// its family. Always false unless the families were set, see `set_family`.
+    //
+    // https://github.com/torproject/torspec/blob/main/dir-spec.txt
+    // 2.1.1. Server descriptor format
+    //    "family" names NL
+    //       [At most once]
+    //       'Names' is a space-separated list of relay nicknames or
+    //       hexdigests. If two ORs list one another in their "family" entries,
+    //       then OR A will treat OR B as a member of its family, and vice versa.
+    pub fn is_in_family_with(&self, other: &OnionRouter) -> bool {
+        self.lists_in_family(other) && other.lists_in_family(self)
+    }
+
+    // Whether `other` is in the family of the onion router, by its "$"-prefixed fingerprint,
+    // optionally followed by "=" or "~" and the nickname, or by its nickname.
+    fn lists_in_family(&self, other: &OnionRouter) -> bool {
+        self.family.iter().any(|name| match name.strip_prefix('$') {
+            Some(hex) => hex
+                .split(['=', '~'])
+                .next()
+                .is_some_and(|hex| hex.eq_ignore_ascii_case(&other.fingerprint())),
+            None => name.eq_ignore_ascii_case(&other.nickname),
+        })
+    }
+
+    /// The address to connect to the onion router's OR port.
+    pub fn ip(&self) -> Ipv4Addr {
+        self.ip
+    }
+
+    pub fn or_port(&self) -> u16 {
+        self.or_port
+    }
+
+    /// The port of the onion router's directory. 0 if it has none.
+    pub fn dir_port(&self) -> u16 {
+        self.dir_port
+    }
+
+    pub fn or_socket_addr(&self) -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(self.ip, self.or_port))
+    }
+
+    /// All the addresses to connect to the onion router's OR port, the IPv4 one in the "r" line
+    /// first, e.g. `[2001:db8::1]:9001` as well for a dual-stack onion router.
+    pub fn or_addresses(&self) -> &[SocketAddr] {
+        &self.or_addresses
+    }
+
+    /// The first IPv6 address of the onion router's OR port, if it has one.
+    pub fn ipv6_or_addr(&self) -> Option<SocketAddr> {
+        self.or_addresses
+            .iter()
+            .find(|address| address.is_ipv6())
+            .copied()
+    }
+
+    /// The URL of the onion router's directory, e.g. `http://171.25.193.9:80`.
+    /// Returns `None` if the onion router has no dir port.
+    pub fn dir_base_url(&self) -> Option<String> {
+        if self.dir_port == 0 {
+            return None;
+        }
+
+        Some(format!("http://{}:{}", self.ip, self.dir_port))
+    }
+
+    pub fn microdesc_digest(&self) -> Option<&str> {
+        self.microdesc_digest.as_deref()
+    }
+
+    /// The version of Tor the onion router runs, e.g. `Tor 0.4.7.13`, if its entry has a "v"
+    /// line.
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    pub fn bandwidth(&self) -> Option<u32> {
+        self.bandwidth
+    }
+
+    /// Whether the bandwidth was measured by enough bandwidth authorities, as opposed to being
+    /// self-advertised by the onion router.
+    pub fn is_bandwidth_measured(&self) -> bool {
+        !self.unmeasured
+    }
+
+    /// Whether the exit policy summary allows exiting to the port.
+    /// An onion router without a "p" line rejects all ports.
+    pub fn allows_exit_port(&self, port: u16) -> bool {
+        match &self.exit_policy {
+            Some(policy) => policy.allows(port),
+            None => false,
+        }
+    }
+
+    /// Whether the "pr" line lists `version` of the protocol, e.g. `Link` 5.
+    pub fn supports_protocol(&self, protocol: &str, version: u32) -> bool {
+        self.protocols.supports(protocol, version)
+    }
+}
+
+/// The exit policy summary in the "p" line.
+// https://github.com/torproject/torspec/blob/main/dir-spec.txt
+//    "p" SP ("accept" / "reject") SP PortList NL
+//
+//        [At most once.]
+//
+//        PortList = PortOrRange
+//        PortList = PortList "," PortOrRange
+//        PortOrRange = INT "-" INT / INT
+//
+//        A list of those ports that this router supports (if 'accept')
+//        or does not support (if 'reject') for exit to "most
+//        addresses".
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) enum ExitPolicy {
+    Accept(Vec<RangeInclusive<u16>>),
+    Reject(Vec<RangeInclusive<u16>>),
+}
+
+impl ExitPolicy {
+    // Parses the fields following the "p" keyword.
+    fn parse(strs: &[&str]) -> Option<ExitPolicy> {
+        if strs.len() != 2 {
+            return None;
+        }
+
+        let ports = parse_ranges(strs[1])?;
+        match strs[0] {
+            "accept" => Some(ExitPolicy::Accept(ports)),
+            "reject" => Some(ExitPolicy::Reject(ports)),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn allows(&self, port: u16) -> bool {
+        match self {
+            ExitPolicy::Accept(ports) => ports.iter().any(|range| range.contains(&port)),
+            ExitPolicy::Reject(ports) => !ports.iter().any(|range| range.contains(&port)),
+        }
+    }
+}
+
+impl fmt::Display for ExitPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitPolicy::Accept(ports) => write!(f, "accept {}", format_ranges(ports)),
+            ExitPolicy::Reject(ports) => write!(f, "reject {}", format_ranges(ports)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FlagError {
+    /// The flags are set on an onion router without the Running flag.
+    NotRunning(Flags),
+    /// The flags can't be set together.
+    Conflicting(Flags, Flags),
+    UnknownFlag(String),
+}
+
+/// The versions of each subprotocol the onion router supports, from the "pr" line.
+// https://github.com/torproject/torspec/blob/main/dir-spec.txt
+//    "pr" SP Entries NL
+//
+// https://github.com/torproject/torspec/blob/main/tor-spec.txt
+// 9. Subprotocol versioning
+//    Entries := Entry
+//    Entries := Entry SP Entries
+//    Entry := Keyword "=" Values
+//    Values := Value
+//    Values := Value "," Values
+//    Value := Int
+//    Value := Int "-" Int
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Protocols(HashMap<String, Vec<RangeInclusive<u32>>>);
+
+impl Protocols {
+    // Parses the fields following the "pr" keyword.
+    fn parse(strs: &[&str]) -> Option<Protocols> {
+        let mut protocols = HashMap::new();
+        for entry in strs {
+            let (keyword, values) = entry.split_once('=')?;
+            protocols.insert(keyword.to_string(), parse_ranges(values)?);
+        }
+
+        Some(Protocols(protocols))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn supports(&self, protocol: &str, version: u32) -> bool {
+        match self.0.get(protocol) {
+            Some(versions) => versions.iter().any(|range| range.contains(&version)),
+            None => false,
+        }
+    }
+
+    /// Whether `min_version` or any later version of the protocol is supported.
+    pub(crate) fn supports_at_least(&self, protocol: &str, min_version: u32) -> bool {
+        match self.0.get(protocol) {
+            Some(versions) => versions.iter().any(|range| *range.end() >= min_version),
+            None => false,
+        }
+    }
+}
+
+impl fmt::Display for Protocols {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Entries are sorted by keyword in the "pr" line.
+        let mut keywords = self.0.keys().collect::<Vec<_>>();
+        keywords.sort_unstable();
+        let entries = keywords
+            .into_iter()
+            .map(|keyword| format!("{}={}", keyword, format_ranges(&self.0[keyword])))
+            .collect::<Vec<_>>();
+        write!(f, "{}", entries.join(" "))
+    }
+}
+
+// Parses a comma-separated list of integers and ranges, e.g. "1-3,5".
+fn parse_ranges<T: FromStr + Copy>(s: &str) -> Option<Vec<RangeInclusive<T>>> {
+    let mut ranges = vec![];
+    for int_or_range in s.split(',') {
+        let range = match int_or_range.split_once('-') {
+            Some((start, end)) => start.parse().ok()?..=end.parse().ok()?,
+            None => {
+                let i = int_or_range.parse().ok()?;
+                i..=i
+            }
+        };
+        ranges.push(range);
+    }
+
+    Some(ranges)
+}
+
+// The inverse of `parse_ranges`.
+fn format_ranges<T: fmt::Display + PartialEq>(ranges: &[RangeInclusive<T>]) -> String {
+    ranges
+        .iter()
+        .map(|range| {
+            if range.start() == range.end() {
+                range.start().to_string()
+            } else {
+                format!("{}-{}", range.start(), range.end())
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+bitflags! {
+    /// The bits are stable: a flag keeps its bit, and new flags take unused bits.
+    pub struct Flags: u32 {
+        const AUTHORITY = 0b0000000000001;
+        const BAD_EXIT = 0b0000000000010;
+        const EXIT = 0b0000000000100;
+        const FAST = 0b0000000001000;
+        const GUARD = 0b0000000010000;
+        const HS_DIR = 0b0000000100000;
+        const MIDDLE_ONLY = 0b0000001000000;
+        const NO_ED_CONSENSUS = 0b0000010000000;
+        const STABLE = 0b0000100000000;
+        const STALE_DESC = 0b0001000000000;
+        const RUNNING = 0b0010000000000;
+        const VALID = 0b0100000000000;
+        const V2DIR = 0b1000000000000;
+    }
+}
+
+// The flags as they are named in the "s" line, in the order they are listed there: sorted by
+// name.
+const FLAG_NAMES: [(Flags, &str); 13] = [
+    (Flags::AUTHORITY, "Authority"),
+    (Flags::BAD_EXIT, "BadExit"),
+    (Flags::EXIT, "Exit"),
+    (Flags::FAST, "Fast"),
+    (Flags::GUARD, "Guard"),
+    (Flags::HS_DIR, "HSDir"),
+    (Flags::MIDDLE_ONLY, "MiddleOnly"),
+    (Flags::NO_ED_CONSENSUS, "NoEdConsensus"),
+    (Flags::RUNNING, "Running"),
+    (Flags::STABLE, "Stable"),
+    (Flags::STALE_DESC, "StaleDesc"),
+    (Flags::V2DIR, "V2Dir"),
+    (Flags::VALID, "Valid"),
+];
+
+impl Flags {
+    /// Parses a comma-separated list of flag names, e.g. `"Guard,Fast,Stable"`.
+    pub fn from_comma_list(s: &str) -> Result<Flags, FlagError> {
+        let mut flags = Flags::empty();
+        for name in s.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            flags.insert(Flags::try_from(name)?);
+        }
+
+        Ok(flags)
+    }
+
+    // The names of the flags in the order of `FLAG_NAMES`.
+    fn names(self) -> Vec<&'static str> {
+        FLAG_NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+
+    /// The inverse of `from_comma_list`. The names are in the same order as in `Display`.
+    pub fn to_comma_list(self) -> String {
+        self.names().join(",")
+    }
+}
+
+impl TryFrom<&str> for Flags {
+    type Error = FlagError;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match FLAG_NAMES.iter().find(|(_, name)| *name == s) {
+            Some((flag, _)) => Ok(*flag),
+            None => Err(FlagError::UnknownFlag(s.to_string())),
+        }
+    }
+}
+
+/// The space-separated flag names as in the "s" line, e.g. `Fast Guard Running Stable Valid`.
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.names().join(" "))
+    }
+}
+
+/// Serialized as the list of flag names, e.g. `["Fast", "Guard"]`, rather than the bits.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Flags {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.names())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Flags {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut flags = Flags::empty();
+        for name in Vec::<String>::deserialize(deserializer)? {
+            flags.insert(
+                Flags::try_from(name.as_str())
+                    .map_err(|e| serde::de::Error::custom(format!("{:?}", e)))?,
+            );
+        }
+
+        Ok(flags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn pages_through_the_relays() {
+        let consensus = parse_consensus_document(&document_with_relays(10)).unwrap();
+        let nicknames = |page: &[OnionRouter]| {
+            page.iter()
+                .map(|or| or.nickname().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            vec!["relay1", "relay2", "relay3", "relay4"],
+            nicknames(consensus.page(0, 4))
+        );
+        assert_eq!(
+            vec!["relay5", "relay6", "relay7", "relay8"],
+            nicknames(consensus.page(4, 4))
+        );
+        assert_eq!(vec!["relay9", "relay10"], nicknames(consensus.page(8, 4)));
+        assert!(consensus.page(12, 4).is_empty());
+    }
+
+    #[test]
+    fn strict_flags_reject_a_guard_that_isnt_running() {
+        let document = document(&[Relay::new(1).only_flags("Fast Guard Stable Valid")]);
+        let options = ParseOptions {
+            strict_flags: true,
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            parse_consensus_document_with_options(&document, &options),
+            Err(ParseError::InvalidFlags(nickname, FlagError::NotRunning(Flags::GUARD)))
+                if nickname == "relay1"
+        ));
+        assert!(parse_consensus_document(&document).is_ok());
+    }
+
+    #[test]
+    fn streams_the_relays_to_the_callback() {
+        let document = document(&[
+            Relay::new(1).bandwidth(Some(100)),
+            Relay::new(2).bandwidth(Some(200)),
+            Relay::new(3).bandwidth(Some(300)),
+        ]);
+
+        let mut total = 0;
+        let consensus = parse_consensus_foreach(&document, |or| {
+            total += or.bandwidth().unwrap_or(0);
+        })
+        .unwrap();
+
+        assert_eq!(600, total);
+        assert_eq!(0, consensus.relay_count());
+    }
+
+    #[test]
+    fn reports_a_reader_failing_mid_stream() {
+        // Fails every read, after the header has been read.
+        struct Failing;
+        impl std::io::Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionReset,
+                    "cut off",
+                ))
+            }
+        }
+        let header = header(Flavor::Microdesc);
+        let reader = std::io::BufReader::new(std::io::Read::chain(header.as_bytes(), Failing));
+
+        assert!(matches!(
+            parse_consensus_document_from_reader(reader, &ParseOptions::default()),
+            Err(ReadError::Io(e)) if e.kind() == std::io::ErrorKind::ConnectionReset
+        ));
+    }
+
+    #[test]
+    fn round_trips_flags_through_a_comma_list() {
+        let flags = Flags::from_comma_list("Guard,Fast").unwrap();
+
+        assert_eq!(Flags::GUARD | Flags::FAST, flags);
+        assert_eq!("Fast,Guard", flags.to_comma_list());
+        assert_eq!(
+            flags,
+            Flags::from_comma_list(&flags.to_comma_list()).unwrap()
+        );
+
+        // The flags are listed in the same order as in the "s" line.
+        let flags = Flags::from_comma_list("Valid,V2Dir,Stable,StaleDesc,Running").unwrap();
+        assert_eq!(
+            "Running,Stable,StaleDesc,V2Dir,Valid",
+            flags.to_comma_list()
+        );
+        assert_eq!(flags.to_string().replace(' ', ","), flags.to_comma_list());
+    }
+
+    #[test]
+    fn counts_the_exits_per_port() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Exit").line("p accept 80,443"),
+            Relay::new(2).flags("Exit").line("p reject 25"),
+            Relay::new(3).flags("Exit BadExit").line("p accept 1-65535"),
+            Relay::new(4).line("p accept 1-65535"),
+        ]))
+        .unwrap();
+
+        let coverage = consensus.exit_port_coverage(&[80, 25]);
+
+        assert_eq!(Some(&2), coverage.get(&80));
+        assert_eq!(Some(&0), coverage.get(&25));
+    }
+
+    #[tokio::test]
+    async fn evicts_the_oldest_entry_past_the_cap() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache_dir = cache_dir.path().to_str().unwrap();
+        cacache::write(cache_dir, "old", vec![b'a'; 100])
+            .await
+            .unwrap();
+        // Entries are ordered by their write time in milliseconds.
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        cacache::write(cache_dir, "new", vec![b'b'; 100])
+            .await
+            .unwrap();
+
+        evict_cache_entries(cache_dir, 150).await;
+
+        assert!(cacache::read(cache_dir, "old").await.is_err());
+        assert_eq!(
+            vec![b'b'; 100],
+            cacache::read(cache_dir, "new").await.unwrap()
+        );
+    }
+
+    #[test]
+    fn excludes_guards_without_the_link_protocol_version() {
+        let old = Relay::new(1).flags("Guard").line("pr Cons=1-2 Link=1-3");
+        let new = Relay::new(2).flags("Guard").line("pr Cons=1-2 Link=1-5");
+        let options = SelectionOptions::default();
+
+        let consensus = parse_consensus_document(&document(&[old.clone(), new])).unwrap();
+        for _ in 0..10 {
+            let guard = consensus
+                .choose_guard_relay_with_link_protocol(4, &options)
+                .unwrap();
+            assert_eq!("relay2", guard.nickname());
+        }
+
+        let consensus = parse_consensus_document(&document(&[old])).unwrap();
+        assert!(consensus
+            .choose_guard_relay_with_link_protocol(4, &options)
+            .is_err());
+    }
+
+    #[test]
+    fn writes_the_fingerprint_file_format() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1),
+            Relay::new(0xab).nickname("other"),
+        ]))
+        .unwrap();
+
+        let mut out = vec![];
+        consensus.write_fingerprints(&mut out).unwrap();
+
+        assert_eq!(
+            "relay1 0101 0101 0101 0101 0101 0101 0101 0101 0101 0101\n\
+             other ABAB ABAB ABAB ABAB ABAB ABAB ABAB ABAB ABAB ABAB\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn tolerates_skew_past_valid_until() {
+        let consensus = parse_consensus_document(&document_with_relays(1)).unwrap();
+        let now = datetime(VALID_UNTIL) + Duration::minutes(1);
+
+        assert!(consensus.is_valid_at(&now, Duration::minutes(2)));
+        assert!(matches!(
+            consensus.check_validity_at(&now, Duration::zero()),
+            Err(ValidityError::Expired(valid_until)) if valid_until == datetime(VALID_UNTIL)
+        ));
+    }
+
+    #[test]
+    fn is_valid_exactly_at_the_bounds_of_the_window() {
+        let consensus = parse_consensus_document(&document_with_relays(1)).unwrap();
+        let valid_after = datetime(VALID_AFTER);
+        let valid_until = datetime(VALID_UNTIL);
+        let second = Duration::seconds(1);
+
+        assert!(consensus.is_valid_at(&valid_after, Duration::zero()));
+        assert!(consensus.is_valid_at(&valid_until, Duration::zero()));
+        assert_eq!(
+            Err(ValidityError::NotYetValid(valid_after)),
+            consensus.check_validity_at(&(valid_after - second), Duration::zero())
+        );
+        assert_eq!(
+            Err(ValidityError::Expired(valid_until)),
+            consensus.check_validity_at(&(valid_until + second), Duration::zero())
+        );
+    }
+
+    #[test]
+    fn widens_the_window_by_exactly_the_tolerance() {
+        let consensus = parse_consensus_document(&document_with_relays(1)).unwrap();
+        let valid_after = datetime(VALID_AFTER);
+        let valid_until = datetime(VALID_UNTIL);
+        let tolerance = Duration::minutes(2);
+        let second = Duration::seconds(1);
+
+        assert!(consensus.is_valid_at(&(valid_after - tolerance), tolerance));
+        assert!(consensus.is_valid_at(&(valid_until + tolerance), tolerance));
+        assert_eq!(
+            Err(ValidityError::NotYetValid(valid_after)),
+            consensus.check_validity_at(&(valid_after - tolerance - second), tolerance)
+        );
+        assert_eq!(
+            Err(ValidityError::Expired(valid_until)),
+            consensus.check_validity_at(&(valid_until + tolerance + second), tolerance)
+        );
+    }
+
+    #[test]
+    fn excludes_unmeasured_relays_from_weighted_selection() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1)
+                .flags("Guard")
+                .bandwidth(None)
+                .line("w Bandwidth=1000000 Unmeasured=1"),
+            Relay::new(2).flags("Guard").bandwidth(Some(1)),
+        ]))
+        .unwrap();
+        let options = SelectionOptions {
+            measured_only: true,
+            ..Default::default()
+        };
+
+        assert!(!consensus.onion_routers()[0].is_bandwidth_measured());
+        for _ in 0..10 {
+            assert_eq!(
+                "relay2",
+                consensus.choose_guard_relay(&options).unwrap().nickname()
+            );
+        }
+    }
+
+    #[test]
+    fn counts_the_flag_combinations() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard"),
+            Relay::new(2).flags("Guard"),
+            Relay::new(3).flags("Exit"),
+            Relay::new(4),
+        ]))
+        .unwrap();
+        let stable = Flags::FAST | Flags::RUNNING | Flags::STABLE | Flags::VALID;
+
+        let combinations = consensus.flag_combinations();
+
+        assert_eq!(3, combinations.len());
+        assert_eq!(Some(&2), combinations.get(&(stable | Flags::GUARD)));
+        assert_eq!(Some(&1), combinations.get(&(stable | Flags::EXIT)));
+        assert_eq!(Some(&1), combinations.get(&stable));
+    }
+
+    #[test]
+    fn counts_the_skipped_relays() {
+        let document = document(&[
+            Relay::new(1),
+            Relay::new(2).only_flags("Fast Running Valid"),
+            Relay::new(3),
+        ]);
+
+        let (consensus, stats) =
+            parse_consensus_document_with_stats(&document, &ParseOptions::default()).unwrap();
+
+        assert_eq!(2, consensus.relay_count());
+        assert_eq!(2, stats.relays_kept);
+        assert_eq!(1, stats.relays_skipped);
+        assert_eq!(document.lines().count(), stats.lines_processed);
+        assert_eq!(document.len(), stats.bytes_processed);
+    }
+
+    #[test]
+    fn never_returns_an_excluded_guard() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard").bandwidth(Some(1_000_000)),
+            Relay::new(2).flags("Guard").bandwidth(Some(1)),
+        ]))
+        .unwrap();
+        let options = SelectionOptions {
+            exclude: HashSet::from([fingerprint(1)]),
+            ..Default::default()
+        };
+
+        for _ in 0..10 {
+            assert_eq!(
+                "relay2",
+                consensus.choose_guard_relay(&options).unwrap().nickname()
+            );
+        }
+    }
+
+    #[test]
+    fn skips_relays_published_in_the_future() {
+        let document = document(&[
+            Relay::new(1),
+            Relay::new(2).published("2022-10-01 02:00:00"),
+        ]);
+        let options = ParseOptions {
+            max_publication_skew: Some(Duration::hours(1)),
+            ..Default::default()
+        };
+
+        let (consensus, stats) = parse_consensus_document_with_stats(&document, &options).unwrap();
+
+        assert_eq!(1, consensus.relay_count());
+        assert_eq!("relay1", consensus.onion_routers()[0].nickname());
+        assert_eq!(1, stats.relays_skipped);
+        assert_eq!(
+            vec!["Skipped onion router relay2 published in the future: 2022-10-01 02:00:00 UTC"],
+            stats.warnings
+        );
+        assert_eq!(
+            2,
+            parse_consensus_document(&document).unwrap().relay_count()
+        );
+    }
+
+    #[test]
+    fn writes_a_consensus_without_relays_as_it_was() {
+        let document = document(&[]);
+        let consensus = parse_consensus_document(&document).unwrap();
+
+        let mut out = vec![];
+        consensus.write_document(&mut out).unwrap();
+
+        assert_eq!(document, String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn writes_every_parsed_line_of_an_entry() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1)
+                .bandwidth(None)
+                .line("v Tor 0.4.7.13")
+                .line("pr Link=1-5 Relay=1-4")
+                .line("w Bandwidth=1000 Measured=900 Unmeasured=1"),
+            Relay::new(2).line("pr"),
+        ]))
+        .unwrap();
+
+        let mut out = vec![];
+        consensus.write_document(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let reparsed = parse_consensus_document(&out).unwrap();
+        let mut rewritten = vec![];
+        reparsed.write_document(&mut rewritten).unwrap();
+
+        assert_eq!(out, String::from_utf8(rewritten).unwrap());
+        assert_eq!(Some("Tor 0.4.7.13"), reparsed.onion_routers()[0].version());
+        assert!(out.contains("\nv Tor 0.4.7.13\n"));
+        assert!(out.contains("\nw Bandwidth=1000 Measured=900 Unmeasured=1\n"));
+        // The empty "pr" line isn't written.
+        assert_eq!(1, out.matches("\npr ").count());
+    }
+
+    #[test]
+    fn writes_a_filtered_subset_that_parses_again() {
+        let mut consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard"),
+            Relay::new(2),
+            Relay::new(3).flags("Guard").line("p accept 80,443"),
+            Relay::new(4),
+            Relay::new(5).flags("Guard"),
+        ]))
+        .unwrap();
+
+        consensus.retain_relays(|or| or.flags().contains(Flags::GUARD));
+        let mut out = vec![];
+        consensus.write_document(&mut out).unwrap();
+        let reparsed = parse_consensus_document(&String::from_utf8(out).unwrap()).unwrap();
+
+        let nicknames = reparsed
+            .onion_routers()
+            .iter()
+            .map(|or| or.nickname())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["relay1", "relay3", "relay5"], nicknames);
+        assert!(reparsed.onion_routers()[1].allows_exit_port(443));
+        assert_eq!(consensus.valid_until_epoch(), reparsed.valid_until_epoch());
+        assert_eq!(
+            consensus.signing_authorities(),
+            reparsed.signing_authorities()
+        );
+    }
+
+    #[test]
+    fn promotes_the_lenient_warnings_to_errors() {
+        let strict = ParseOptions {
+            warnings_as_errors: true,
+            ..Default::default()
+        };
+
+        let unknown_flag = document(&[Relay::new(1).flags("Unheard")]);
+        assert_eq!(
+            1,
+            parse_consensus_document(&unknown_flag)
+                .unwrap()
+                .relay_count()
+        );
+        assert!(matches!(
+            parse_consensus_document_with_options(&unknown_flag, &strict),
+            Err(ParseError::InvalidFlags(_, FlagError::UnknownFlag(flag))) if flag == "Unheard"
+        ));
+
+        let unstable = document(&[
+            Relay::new(1),
+            Relay::new(2).only_flags("Fast Running Valid"),
+        ]);
+        assert_eq!(
+            1,
+            parse_consensus_document(&unstable).unwrap().relay_count()
+        );
+        assert!(matches!(
+            parse_consensus_document_with_options(&unstable, &strict),
+            Err(ParseError::SkippedRelay(nickname)) if nickname == "relay2"
+        ));
+    }
+
+    #[test]
+    fn tells_whether_a_version_is_recommended() {
+        let consensus = parse_consensus_document(&document_with_relays(1)).unwrap();
+
+        assert!(consensus.is_client_version_recommended("0.4.7.13"));
+        assert!(!consensus.is_client_version_recommended("0.3.5.7"));
+        assert!(consensus.is_server_version_recommended("0.4.8.4"));
+        assert!(!consensus.is_server_version_recommended("0.4.8"));
+    }
+
+    #[test]
+    fn avoids_the_relays_with_a_chosen_flag() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1)
+                .flags("Guard HSDir")
+                .bandwidth(Some(1_000_000)),
+            Relay::new(2).flags("Guard").bandwidth(Some(1)),
+        ]))
+        .unwrap();
+        let options = SelectionOptions {
+            avoid_flags: Some(Flags::HS_DIR),
+            ..Default::default()
+        };
+
+        assert_eq!(1, consensus.candidates(Flags::GUARD, &options).len());
+        for _ in 0..10 {
+            assert_eq!(
+                "relay2",
+                consensus.choose_guard_relay(&options).unwrap().nickname()
+            );
+        }
+    }
+
+    #[test]
+    fn converts_the_validity_window_to_unix_time() {
+        let consensus = parse_consensus_document(&document_with_relays(1)).unwrap();
+
+        // 2022-10-01 00:00:00 and 03:00:00 UTC.
+        assert_eq!(1_664_582_400, consensus.valid_after_epoch());
+        assert_eq!(1_664_593_200, consensus.valid_until_epoch());
+    }
+
+    #[test]
+    fn flags_a_consensus_truncated_at_the_limit() {
+        let consensus = parse_consensus_document(&document_with_relays(101)).unwrap();
+        assert!(consensus.is_truncated());
+        assert_eq!(ONION_ROUTER_LIMIT, consensus.relay_count());
+
+        let consensus = parse_consensus_document(&document_with_relays(100)).unwrap();
+        assert!(!consensus.is_truncated());
+        assert_eq!(100, consensus.relay_count());
+    }
+
+    #[test]
+    fn chooses_uniformly_without_bandwidths() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard").bandwidth(None),
+            Relay::new(2).flags("Guard").bandwidth(None),
+        ]))
+        .unwrap();
+        let candidates = consensus.candidates(Flags::GUARD, &SelectionOptions::default());
+        let mut rng = StdRng::seed_from_u64(1);
+
+        let mut chosen = HashSet::new();
+        for _ in 0..20 {
+            let or = consensus
+                .choose_by_weight_with_rng(&candidates, Position::Guard, &mut rng)
+                .unwrap();
+            chosen.insert(or.nickname());
+        }
+
+        assert_eq!(HashSet::from(["relay1", "relay2"]), chosen);
+    }
+
+    #[tokio::test]
+    async fn caches_the_authority_along_with_the_document() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache_dir = cache_dir.path().to_str().unwrap();
+        let document = document_with_relays(1);
+
+        cache_consensus_document(
+            cache_dir,
+            Flavor::Microdesc,
+            &document,
+            "moria1",
+            &datetime(VALID_UNTIL),
+            DEFAULT_CACHE_SIZE_CAP,
+        )
+        .await;
+        let cached =
+            get_consensus_document_from_cache(cache_dir, Flavor::Microdesc, &datetime(VALID_AFTER))
+                .await
+                .unwrap();
+
+        assert_eq!(document, cached.body);
+        assert_eq!(Some("moria1".to_string()), cached.authority);
+        assert!(
+            get_consensus_document_from_cache(cache_dir, Flavor::Ns, &datetime(VALID_AFTER))
+                .await
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn parses_documents_separated_by_a_blank_line() {
+        let body = format!("{}\n{}", document_with_relays(1), document_with_relays(2));
+
+        let documents = parse_consensus_documents(&body);
+
+        assert_eq!(2, documents.len());
+        assert_eq!(1, documents[0].as_ref().unwrap().relay_count());
+        assert_eq!(2, documents[1].as_ref().unwrap().relay_count());
+    }
+
+    #[test]
+    fn rejects_unsorted_relays_only_when_asked_to() {
+        let document = document(&[Relay::new(2), Relay::new(1)]);
+        let options = ParseOptions {
+            sorted_relays: true,
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            parse_consensus_document_with_options(&document, &options),
+            Err(ParseError::UnsortedRelays(nickname)) if nickname == "relay1"
+        ));
+        assert_eq!(
+            2,
+            parse_consensus_document(&document).unwrap().relay_count()
+        );
+    }
+
+    #[test]
+    fn counts_the_relays_by_flag_in_the_network_stats() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard").bandwidth(Some(300)),
+            Relay::new(2).flags("Guard Exit HSDir").bandwidth(Some(100)),
+            Relay::new(3).flags("Exit").bandwidth(Some(200)),
+            Relay::new(4).bandwidth(None),
+        ]))
+        .unwrap();
+
+        let stats = consensus.network_stats();
+
+        assert_eq!("microdesc", stats.flavor);
+        assert_eq!(4, stats.relay_count);
+        assert_eq!(2, stats.guard_count);
+        assert_eq!(2, stats.exit_count);
+        assert_eq!(1, stats.hsdir_count);
+        assert_eq!(600, stats.total_bandwidth);
+        assert_eq!(100, stats.median_bandwidth);
+    }
+
+    #[test]
+    fn accepts_the_oldest_supported_consensus_method() {
+        let document = document_with_relays(1).replace(
+            "consensus-method 32",
+            &format!("consensus-method {}", MIN_SUPPORTED_CONSENSUS_METHOD),
+        );
+
+        assert_eq!(
+            MIN_SUPPORTED_CONSENSUS_METHOD,
+            parse_consensus_document(&document)
+                .unwrap()
+                .consensus_method()
+        );
+    }
+
+    #[test]
+    fn rejects_a_consensus_method_that_is_too_old() {
+        let method = format!("consensus-method {}", MIN_SUPPORTED_CONSENSUS_METHOD - 1);
+        let document = document_with_relays(1).replace("consensus-method 32", &method);
+
+        assert!(matches!(
+            parse_consensus_document(&document),
+            Err(ParseError::UnsupportedConsensusMethod(line)) if line == method
+        ));
+    }
+
+    #[test]
+    fn rejects_a_malformed_vote_status_with_the_line() {
+        for vote_status in [
+            "vote-status",
+            "vote-status vote",
+            "vote-status consensus extra",
+        ] {
+            let document = document_with_relays(1).replace("vote-status consensus", vote_status);
+
+            assert!(matches!(
+                parse_consensus_document(&document),
+                Err(ParseError::UnexpectedVoteStatus(line)) if line == vote_status
+            ));
+        }
+    }
+
+    #[test]
+    fn retains_an_invalid_relay_but_selects_it_only_when_allowed() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard"),
+            Relay::new(2).only_flags("Fast Guard Running Stable"),
+        ]))
+        .unwrap();
+        let allow_invalid = SelectionOptions {
+            allow_invalid: true,
+            ..Default::default()
+        };
+
+        assert_eq!(2, consensus.relay_count());
+        let nicknames = |options: &SelectionOptions| {
+            consensus
+                .candidates(Flags::GUARD, options)
+                .iter()
+                .map(|or| or.nickname().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec!["relay1"], nicknames(&SelectionOptions::default()));
+        assert_eq!(vec!["relay1", "relay2"], nicknames(&allow_invalid));
+    }
+
+    #[test]
+    fn parses_the_bandwidth_file_lines() {
+        let document = document_with_relays(1).replace(
+            "params ",
+            "bandwidth-file-headers timestamp=1664582000 version=1.4.0\n\
+             bandwidth-file-digest sha256=3f3Cz5K2 sha512=0XAx6P9g\n\
+             params ",
+        );
+
+        let consensus = parse_consensus_document(&document).unwrap();
+
+        let headers = consensus.bandwidth_file_headers.unwrap();
+        assert_eq!(
+            Some("1664582000"),
+            headers.get("timestamp").map(String::as_str)
+        );
+        assert_eq!(Some("1.4.0"), headers.get("version").map(String::as_str));
+        let digest = consensus.bandwidth_file_digest.unwrap();
+        assert_eq!(Some("3f3Cz5K2"), digest.get("sha256").map(String::as_str));
+        assert_eq!(Some("0XAx6P9g"), digest.get("sha512").map(String::as_str));
+
+        let consensus = parse_consensus_document(&document_with_relays(1)).unwrap();
+        assert!(consensus.bandwidth_file_headers.is_none());
+        assert!(consensus.bandwidth_file_digest.is_none());
+    }
+
+    #[test]
+    fn parses_the_signing_authorities() {
+        let consensus = parse_consensus_document(&document_with_relays(1)).unwrap();
+
+        assert_eq!(vec![SIGNING_AUTHORITY], consensus.signing_authorities());
+    }
+
+    #[test]
+    fn parses_the_footer_past_the_limit() {
+        let consensus = parse_consensus_document(&document_with_relays(101)).unwrap();
+
+        assert!(consensus.is_truncated());
+        assert_eq!(vec![SIGNING_AUTHORITY], consensus.signing_authorities());
+        assert!(consensus.bandwidth_weights.is_some());
+        assert_eq!("directory-footer", consensus.footer[0]);
+    }
+
+    #[test]
+    fn excludes_the_relays_below_the_bandwidth_percentile() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).bandwidth(Some(400)),
+            Relay::new(2).bandwidth(Some(100)),
+            Relay::new(3).bandwidth(Some(300)),
+            Relay::new(4).bandwidth(Some(200)),
+        ]))
+        .unwrap();
+        let options = SelectionOptions {
+            min_bandwidth_percentile: 0.5,
+            ..Default::default()
+        };
+
+        let nicknames = consensus
+            .candidates(Flags::empty(), &options)
+            .iter()
+            .map(|or| or.nickname().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec!["relay1", "relay3", "relay4"], nicknames);
+    }
+
+    // A document in which relay1 has the identity of moria1's relay, and the address of it if
+    // `genuine`.
+    fn document_with_moria1_identity(genuine: bool) -> String {
+        let moria1 = directory_authorities()
+            .into_iter()
+            .find(|da| da.name == "moria1")
+            .unwrap();
+        let moria1_identity = (0..moria1.fingerprint.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&moria1.fingerprint[i..i + 2], 16).unwrap())
+            .collect::<Vec<_>>();
+        let document = document(&[Relay::new(1).flags("Authority"), Relay::new(2)]).replace(
+            &base64::encode_config(identity(1), base64::STANDARD_NO_PAD),
+            &base64::encode_config(moria1_identity, base64::STANDARD_NO_PAD),
+        );
+        if !genuine {
+            return document;
+        }
+        document.replace(
+            "10.1.0.1 9001",
+            &format!("{} {}", moria1.ip, moria1.or_port),
+        )
+    }
+
+    #[test]
+    fn skips_a_relay_with_the_fingerprint_of_an_authority() {
+        let document = document_with_moria1_identity(false);
+        let options = ParseOptions {
+            skip_authority_impostors: true,
+            ..Default::default()
+        };
+
+        let (consensus, stats) = parse_consensus_document_with_stats(&document, &options).unwrap();
+        assert_eq!(1, consensus.relay_count());
+        assert_eq!("relay2", consensus.onion_routers()[0].nickname());
+        assert_eq!(
+            vec!["Skipped onion router relay1 with the identity of a directory authority"],
+            stats.warnings
+        );
+
+        assert_eq!(
+            2,
+            parse_consensus_document(&document).unwrap().relay_count()
+        );
+        let options = ParseOptions {
+            warnings_as_errors: true,
+            ..options
+        };
+        assert!(matches!(
+            parse_consensus_document_with_options(&document, &options),
+            Err(ParseError::AuthorityIdentityReused(nickname)) if nickname == "relay1"
+        ));
+    }
+
+    #[test]
+    fn keeps_the_relay_of_an_authority() {
+        let document = document_with_moria1_identity(true);
+        let options = ParseOptions {
+            skip_authority_impostors: true,
+            warnings_as_errors: true,
+            ..Default::default()
+        };
+
+        let (consensus, stats) = parse_consensus_document_with_stats(&document, &options).unwrap();
+
+        assert_eq!(2, consensus.relay_count());
+        assert!(stats.warnings.is_empty());
+    }
+
+    #[test]
+    fn round_trips_the_flags_through_their_bits() {
+        let consensus =
+            parse_consensus_document(&document(&[Relay::new(1).flags("Guard Exit HSDir")]))
+                .unwrap();
+        let or = &consensus.onion_routers()[0];
+
+        let bits = or.flags_bits();
+
+        assert_eq!(Some(or.flags()), Flags::from_bits(bits));
+        assert_eq!(
+            Some(Flags::GUARD | Flags::VALID),
+            Flags::from_bits((Flags::GUARD | Flags::VALID).bits())
+        );
+        assert_eq!(None, Flags::from_bits(u32::MAX));
+    }
+
+    #[test]
+    fn parses_the_w_line() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).bandwidth(None).line("w Bandwidth=20"),
+            Relay::new(2)
+                .bandwidth(None)
+                .line("w Bandwidth=30 Unmeasured=1 Measured=25 Future=x"),
+            Relay::new(3).bandwidth(None),
+        ]))
+        .unwrap();
+        let relays = consensus.onion_routers();
+
+        assert_eq!(Some(20), relays[0].bandwidth());
+        assert!(relays[0].is_bandwidth_measured());
+        assert_eq!(Some(30), relays[1].bandwidth());
+        assert!(!relays[1].is_bandwidth_measured());
+        assert_eq!(None, relays[2].bandwidth());
+
+        let document = document(&[Relay::new(1).bandwidth(None).line("w Bandwidth=many")]);
+        assert!(matches!(
+            parse_consensus_document(&document),
+            Err(ParseError::InvalidBandwidth(line)) if line == "w Bandwidth=many"
+        ));
+    }
+
+    #[test]
+    fn rejects_an_entry_line_without_a_preceding_r_line() {
+        for line in [
+            "s Fast",
+            "v Tor 0.4.7.13",
+            "p accept 80",
+            "pr Link=5",
+            "w Bandwidth=1",
+            "m digest",
+        ] {
+            let document =
+                document_with_relays(1).replacen("r relay1", &format!("{}\nr relay1", line), 1);
+
+            assert!(
+                matches!(
+                    parse_consensus_document(&document),
+                    Err(ParseError::NoPrecedingRelay(l)) if l == line
+                ),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn parses_the_microdescriptor_digests_of_two_relays() {
+        let document = document(&[
+            Relay::new(1).line(&m_line(1)),
+            Relay::new(2).line(&m_line(2)),
+        ]);
+
+        let consensus = parse_consensus_document(&document).unwrap();
+
+        let digests = consensus
+            .onion_routers()
+            .iter()
+            .map(|or| format!("m {}", or.microdesc_digest().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![m_line(1), m_line(2)], digests);
+    }
+
+    #[test]
+    fn lists_the_relays_with_both_guard_and_exit_flags() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard"),
+            Relay::new(2).flags("Exit Guard"),
+            Relay::new(3).flags("Exit"),
+            Relay::new(4).flags("Exit Guard HSDir"),
+        ]))
+        .unwrap();
+
+        let nicknames = consensus
+            .guard_and_exit_relays()
+            .iter()
+            .map(|or| or.nickname().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec!["relay2", "relay4"], nicknames);
+    }
+
+    #[test]
+    fn rejects_a_corrupted_r_line() {
+        let valid = document_with_relays(1);
+        let r_line = valid.lines().find(|line| line.starts_with("r ")).unwrap();
+        for corrupted in [
+            // A field is missing.
+            "r relay1 AQEBAQEBAQEBAQEBAQEBAQEBAQE 2022-09-30 12:00:00 10.1.0.1 9001".to_string(),
+            // The identity isn't base64.
+            r_line.replace("AQEBAQEBAQEBAQEBAQEBAQEBAQE", "!!!"),
+            // The ns digest is in a microdesc consensus.
+            r_line.replace("relay1 ", "relay1 AQEBAQEBAQEBAQEBAQEBAQEBAQE "),
+        ] {
+            let document = valid.replace(r_line, &corrupted);
+
+            assert!(
+                matches!(
+                    parse_consensus_document(&document),
+                    Err(ParseError::MalformedRelayLine(line)) if line == corrupted
+                ),
+                "{}",
+                corrupted
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_a_timestamp_with_a_missing_or_an_extra_field() {
+        for valid_after in [
+            "valid-after 2022-10-01",
+            "valid-after 2022-10-01 00:00:00 UTC",
+        ] {
+            let document = document_with_relays(1)
+                .replace(&format!("valid-after {}", VALID_AFTER), valid_after);
+
+            assert!(matches!(
+                parse_consensus_document(&document),
+                Err(ParseError::DateTimeParseError(line, _)) if line == valid_after
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_a_document_missing_a_required_field() {
+        for (field, line) in [
+            ("consensus-method", "consensus-method 32\n".to_string()),
+            ("valid-after", format!("valid-after {}\n", VALID_AFTER)),
+            ("fresh-until", format!("fresh-until {}\n", FRESH_UNTIL)),
+            ("valid-until", format!("valid-until {}\n", VALID_UNTIL)),
+        ] {
+            let document = document_with_relays(1).replace(&line, "");
+
+            assert!(
+                matches!(
+                    parse_consensus_document(&document),
+                    Err(ParseError::MissingField(missing)) if missing == field
+                ),
+                "{}",
+                field
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_the_unknown_sybil_flag() {
+        let document = document(&[Relay::new(1).flags("Guard Sybil Exit")]);
+
+        let consensus = parse_consensus_document(&document).unwrap();
+
+        assert_eq!(
+            Flags::FAST
+                | Flags::RUNNING
+                | Flags::STABLE
+                | Flags::VALID
+                | Flags::GUARD
+                | Flags::EXIT,
+            consensus.onion_routers()[0].flags()
+        );
+        assert!(
+            matches!(Flags::try_from("Sybil"), Err(FlagError::UnknownFlag(flag)) if flag == "Sybil")
+        );
+        let strict = ParseOptions {
+            warnings_as_errors: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            parse_consensus_document_with_options(&document, &strict),
+            Err(ParseError::InvalidFlags(nickname, FlagError::UnknownFlag(flag)))
+                if nickname == "relay1" && flag == "Sybil"
+        ));
+    }
+
+    #[test]
+    fn reports_the_parse_throughput() {
+        let document = document_with_relays(100);
+
+        let (_, stats) =
+            parse_consensus_document_with_stats(&document, &ParseOptions::default()).unwrap();
+
+        assert_eq!(100, stats.relays_kept);
+        assert_eq!(document.lines().count(), stats.lines_processed);
+        assert_eq!(document.len(), stats.bytes_processed);
+        assert!(stats.duration > std::time::Duration::ZERO);
+        assert!(stats.throughput_mb_per_sec() > 0.0);
+        assert_eq!(0.0, ParseStats::default().throughput_mb_per_sec());
+    }
+
+    #[test]
+    fn fails_to_choose_a_guard_without_relays() {
+        let consensus = parse_consensus_document(&document(&[])).unwrap();
+
+        assert!(consensus
+            .choose_guard_relay(&SelectionOptions::default())
+            .is_err());
+    }
+
+    #[test]
+    fn chooses_the_only_guard() {
+        let consensus =
+            parse_consensus_document(&document(&[Relay::new(1).flags("Guard")])).unwrap();
+
+        for _ in 0..10 {
+            let guard = consensus
+                .choose_guard_relay(&SelectionOptions::default())
+                .unwrap();
+            assert_eq!("relay1", guard.nickname());
+        }
+    }
+
+    #[test]
+    fn chooses_an_exit_relay_whose_policy_allows_the_port() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Exit").line("p accept 80,443"),
+            Relay::new(2).flags("Exit").line("p reject 1-442,444-65535"),
+            Relay::new(3).flags("Exit").line("p reject 80,443"),
+        ]))
+        .unwrap();
+        let options = SelectionOptions::default();
+
+        for _ in 0..10 {
+            let exit = consensus.choose_exit_relay(80, &options).unwrap();
+            assert_eq!("relay1", exit.nickname());
+            let exit = consensus.choose_exit_relay(443, &options).unwrap();
+            assert_ne!("relay3", exit.nickname());
+            let exit = consensus.choose_exit_relay(22, &options).unwrap();
+            assert_eq!("relay3", exit.nickname());
+        }
+    }
+
+    #[test]
+    fn chooses_an_exit_relay_respecting_the_options() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Exit").line("p accept 443"),
+            Relay::new(2).flags("Exit BadExit").line("p accept 443"),
+        ]))
+        .unwrap();
+        let exclude = || SelectionOptions {
+            exclude: HashSet::from([fingerprint(1)]),
+            ..Default::default()
+        };
+        let allow_bad_exits = SelectionOptions {
+            avoid_flags: Some(Flags::empty()),
+            ..exclude()
+        };
+        let exclude_subnet = SelectionOptions {
+            excluded_subnets: vec!["10.1.0.0/16".parse().unwrap()],
+            ..Default::default()
+        };
+
+        assert!(consensus.choose_exit_relay(443, &exclude_subnet).is_err());
+        let exit = consensus.choose_exit_relay(443, &allow_bad_exits).unwrap();
+        assert_eq!("relay2", exit.nickname());
+        assert!(consensus.choose_exit_relay(443, &exclude()).is_err());
+    }
+
+    #[test]
+    fn chooses_a_middle_relay_other_than_the_chosen_ones() {
+        let consensus = parse_consensus_document(&document_with_relays(4)).unwrap();
+        let relays = consensus.onion_routers();
+        let chosen = [&relays[0], &relays[1]];
+        let options = SelectionOptions {
+            exclude: HashSet::from([fingerprint(3)]),
+            ..Default::default()
+        };
+
+        for _ in 0..10 {
+            let middle = consensus
+                .choose_middle_relay(&chosen, &SelectionOptions::default())
+                .unwrap();
+            assert!(chosen.iter().all(|or| or.identity() != middle.identity()));
+            let middle = consensus.choose_middle_relay(&chosen, &options).unwrap();
+            assert_eq!("relay4", middle.nickname());
+        }
+        assert!(consensus
+            .choose_middle_relay(&relays.iter().collect::<Vec<_>>(), &options)
+            .is_err());
+    }
+
+    #[test]
+    fn collects_the_unknown_lines_with_their_numbers() {
+        let document = document(&[Relay::new(1).line("future-relay-field 1")])
+            .replace("params ", "future-header-field a b\nparams ");
+        let line_number = |line| document.lines().position(|l| l == line).unwrap() + 1;
+        let options = ParseOptions {
+            collect_unknown: true,
+            ..Default::default()
+        };
+
+        let consensus = parse_consensus_document_with_options(&document, &options).unwrap();
+
+        for line in ["future-header-field a b", "future-relay-field 1"] {
+            assert!(
+                consensus
+                    .unknown_lines()
+                    .contains(&(line_number(line), line.to_string())),
+                "{}",
+                line
+            );
+        }
+        assert!(!consensus
+            .unknown_lines()
+            .iter()
+            .any(|(_, line)| line.starts_with("valid-after") || line.starts_with("r ")));
+        assert!(parse_consensus_document(&document)
+            .unwrap()
+            .unknown_lines()
+            .is_empty());
+    }
+
+    #[test]
+    fn puts_relays_listing_one_another_in_a_family() {
+        let mut consensus = parse_consensus_document(&document_with_relays(3)).unwrap();
+        consensus.onion_routers[0].set_family(vec![
+            format!("${}~relay2", fingerprint(2).to_lowercase()),
+            "relay3".to_string(),
+        ]);
+        consensus.onion_routers[1].set_family(vec![format!("${}", fingerprint(1))]);
+        let relays = consensus.onion_routers();
+
+        assert!(relays[0].is_in_family_with(&relays[1]));
+        assert!(relays[1].is_in_family_with(&relays[0]));
+        // relay3 doesn't list relay1.
+        assert!(!relays[0].is_in_family_with(&relays[2]));
+    }
+
+    #[test]
+    fn weighs_a_guard_and_exit_relay_by_wgd_past_the_limit() {
+        let relays = (1..=101)
+            .map(|id| match id {
+                1 => Relay::new(id).flags("Guard Exit").bandwidth(Some(10_000)),
+                _ => Relay::new(id),
+            })
+            .collect::<Vec<_>>();
+        let consensus = parse_consensus_document(&document(&relays)).unwrap();
+        let or = &consensus.onion_routers()[0];
+
+        // The bandwidth-weights line follows the relays the limit cuts off.
+        assert!(consensus.is_truncated());
+        // Wgd=2500, Wmd=5000 and Wed=2500 of 10000.
+        assert_eq!(2500, consensus.consensus_weight(or, Position::Guard));
+        assert_eq!(5000, consensus.consensus_weight(or, Position::Middle));
+        assert_eq!(2500, consensus.consensus_weight(or, Position::Exit));
+    }
+
+    #[test]
+    fn chooses_in_proportion_to_the_weights() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard").bandwidth(Some(3000)),
+            Relay::new(2).flags("Guard").bandwidth(Some(1000)),
+        ]))
+        .unwrap();
+        let candidates = consensus.candidates(Flags::GUARD, &SelectionOptions::default());
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let draws = 10_000;
+        let heavier = (0..draws)
+            .filter(|_| {
+                consensus
+                    .choose_by_weight_with_rng(&candidates, Position::Guard, &mut rng)
+                    .unwrap()
+                    .nickname()
+                    == "relay1"
+            })
+            .count();
+
+        // Both are weighted by Wgg, so relay1 is chosen 3 times out of 4.
+        let share = heavier as f64 / draws as f64;
+        assert!((share - 0.75).abs() < 0.02, "{}", share);
+    }
+
+    #[test]
+    fn flags_a_relay_whose_a_line_has_another_ipv4() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).line("a 10.1.0.1:9002"),
+            Relay::new(2).line("a 192.0.2.1:9001"),
+            Relay::new(3).line("a [2001:db8::3]:9001"),
+        ]))
+        .unwrap();
+
+        let nicknames = consensus
+            .relays_with_mismatched_ipv4()
+            .iter()
+            .map(|or| or.nickname().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec!["relay2"], nicknames);
+    }
+
+    #[test]
+    fn parses_the_or_addresses_of_ipv4_only_and_dual_stack_relays() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1),
+            Relay::new(2).line("a [2001:db8::2]:9002"),
+        ]))
+        .unwrap();
+        let relays = consensus.onion_routers();
+
+        assert_eq!(
+            ["10.1.0.1:9001".parse::<SocketAddr>().unwrap()],
+            relays[0].or_addresses()
+        );
+        assert_eq!(None, relays[0].ipv6_or_addr());
+        assert_eq!(
+            [
+                "10.2.0.1:9001".parse::<SocketAddr>().unwrap(),
+                "[2001:db8::2]:9002".parse().unwrap()
+            ],
+            relays[1].or_addresses()
+        );
+        assert_eq!(
+            Some("[2001:db8::2]:9002".parse().unwrap()),
+            relays[1].ipv6_or_addr()
+        );
+
+        let document = document(&[Relay::new(1).line("a 2001:db8::1")]);
+        assert!(matches!(
+            parse_consensus_document(&document),
+            Err(ParseError::InvalidAddress(line)) if line == "a 2001:db8::1"
+        ));
+    }
+
+    #[test]
+    fn rotates_the_guard_at_the_end_of_its_lifetime() {
+        let document = document_with_relays(1);
+        let consensus = parse_consensus_document(&document).unwrap();
+        let chosen_at = datetime(VALID_AFTER);
+
+        // guard-lifetime-days=120
+        let end = chosen_at + Duration::days(120);
+        assert_eq!(Duration::days(120), consensus.guard_lifetime());
+        assert!(!consensus.should_rotate_guard(chosen_at, end - Duration::seconds(1)));
+        assert!(consensus.should_rotate_guard(chosen_at, end));
+
+        let without_param =
+            parse_consensus_document(&document.replace(" guard-lifetime-days=120", "")).unwrap();
+        assert_eq!(
+            Duration::days(DEFAULT_GUARD_LIFETIME_DAYS),
+            without_param.guard_lifetime()
+        );
+    }
+
+    #[test]
+    fn parses_an_opt_prefixed_line_like_the_line_itself() {
+        let document = document_with_relays(1).replace("params ", "opt params ");
+
+        let consensus = parse_consensus_document(&document).unwrap();
+
+        assert_eq!(Duration::days(120), consensus.guard_lifetime());
+        assert_eq!(
+            Some(&30000),
+            consensus.params.get("CircuitPriorityHalflifeMsec")
+        );
+    }
+
+    #[test]
+    fn formats_the_flags_as_an_s_line() {
+        let s_line = "Exit Fast Guard HSDir Running Stable V2Dir Valid";
+        let consensus =
+            parse_consensus_document(&document(&[Relay::new(1).only_flags(s_line)])).unwrap();
+
+        let formatted = consensus.onion_routers()[0].flags().to_string();
+
+        let tokens = |line: &str| line.split(' ').map(str::to_string).collect::<HashSet<_>>();
+        assert_eq!(tokens(s_line), tokens(&formatted));
+        assert_eq!("", Flags::empty().to_string());
+    }
+
+    #[test]
+    fn excludes_the_relays_in_an_excluded_subnet() {
+        let consensus = parse_consensus_document(&document_with_relays(3)).unwrap();
+        let options = SelectionOptions {
+            excluded_subnets: vec!["10.2.0.0/16".parse().unwrap()],
+            ..Default::default()
+        };
+
+        let nicknames = consensus
+            .candidates(Flags::empty(), &options)
+            .iter()
+            .map(|or| or.nickname().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec!["relay1", "relay3"], nicknames);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn round_trips_a_consensus_through_json() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard").line("p accept 80,443"),
+            Relay::new(2)
+                .flags("Exit")
+                .line("a [2001:db8::2]:9001")
+                .line(&m_line(2)),
+        ]))
+        .unwrap();
+
+        let json = serde_json::to_value(&consensus).unwrap();
+
+        assert_eq!("2022-10-01T00:00:00Z", json["valid_after"]);
+        assert_eq!(
+            serde_json::json!(["Fast", "Guard", "Running", "Stable", "Valid"]),
+            json["onion_routers"][0]["flags"]
+        );
+        assert_eq!(consensus, serde_json::from_value(json).unwrap());
+    }
+
+    #[test]
+    fn returns_the_weight_the_relay_was_chosen_with() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard").bandwidth(Some(2000)),
+            Relay::new(2).flags("Guard Exit").bandwidth(Some(4000)),
+            Relay::new(3)
+                .flags("Exit")
+                .line("p accept 443")
+                .bandwidth(Some(8000)),
+        ]))
+        .unwrap();
+        let options = SelectionOptions::default();
+
+        for _ in 0..10 {
+            let (guard, weight) = consensus.choose_guard_relay_with_weight(&options).unwrap();
+            assert_eq!(consensus.consensus_weight(guard, Position::Guard), weight);
+            let (exit, weight) = consensus
+                .choose_exit_relay_with_weight(443, &options)
+                .unwrap();
+            assert_eq!(consensus.consensus_weight(exit, Position::Exit), weight);
+            let (middle, weight) = consensus
+                .choose_middle_relay_with_weight(&[guard, exit], &options)
+                .unwrap();
+            assert_eq!(consensus.consensus_weight(middle, Position::Middle), weight);
+        }
+    }
+
+    #[test]
+    fn computes_the_position_bandwidth_fractions_from_the_weights() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard").bandwidth(Some(10000)),
+            Relay::new(2).flags("Exit").bandwidth(Some(10000)),
+            Relay::new(3).bandwidth(Some(10000)),
+        ]))
+        .unwrap();
+
+        // Guard: Wgg for relay 1. Middle: Wmg, Wme and Wmm. Exit: Wee for relay 2.
+        assert_eq!(
+            (5797.0 / 30000.0, 14203.0 / 30000.0, 10000.0 / 30000.0),
+            consensus.position_bandwidth_fraction()
+        );
+    }
+
+    #[test]
+    fn has_no_position_bandwidth_fractions_without_bandwidth() {
+        let consensus = parse_consensus_document(&document(&[Relay::new(1)
+            .flags("Guard Exit")
+            .bandwidth(None)]))
+        .unwrap();
+
+        assert_eq!((0.0, 0.0, 0.0), consensus.position_bandwidth_fraction());
+    }
+
+    #[test]
+    fn puts_the_cache_under_the_home_directory() {
+        assert_eq!(
+            std::path::Path::new("/home/alice")
+                .join(".gants")
+                .display()
+                .to_string(),
+            cache_dir_under(Some("/home/alice".into()))
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_temporary_directory_without_a_home_directory() {
+        assert_eq!(
+            std::env::temp_dir().join(".gants").display().to_string(),
+            cache_dir_under(None)
+        );
+    }
+
+    #[test]
+    fn sorts_the_relays_by_nickname_then_identity() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).nickname("charlie"),
+            Relay::new(3).nickname("alpha"),
+            Relay::new(4).nickname("bravo"),
+            Relay::new(2).nickname("alpha"),
+        ]))
+        .unwrap();
+
+        let sorted = consensus
+            .relays_sorted(SortKey::Nickname)
+            .into_iter()
+            .map(|or| (or.nickname(), or.identity[0]))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![("alpha", 2), ("alpha", 3), ("bravo", 4), ("charlie", 1)],
+            sorted
+        );
+    }
+
+    #[test]
+    fn sorts_the_relays_without_bandwidth_first() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).bandwidth(Some(300)),
+            Relay::new(2).bandwidth(None),
+            Relay::new(3).bandwidth(Some(100)),
+        ]))
+        .unwrap();
+
+        let sorted = consensus
+            .relays_sorted(SortKey::Bandwidth)
+            .into_iter()
+            .map(|or| or.nickname())
+            .collect::<Vec<_>>();
+
+        assert_eq!(vec!["relay2", "relay3", "relay1"], sorted);
+    }
+
+    #[test]
+    fn decodes_the_identity_digest_and_publication_of_the_r_line() {
+        let consensus = parse_consensus_document(&document_of_flavor(
+            Flavor::Ns,
+            &[Relay::new(7).published("2022-09-30 23:59:59")],
+        ))
+        .unwrap();
+        let relay = &consensus.onion_routers[0];
+
+        assert_eq!(20, relay.identity().len());
+        assert_eq!(identity(7), relay.identity());
+        assert_eq!(Some(&[0xd0; 20][..]), relay.digest());
+        assert_eq!(datetime("2022-09-30 23:59:59"), relay.publication());
+    }
+
+    #[test]
+    fn finds_a_relay_by_nickname_ignoring_case() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).nickname("Alpha"),
+            Relay::new(2).nickname("bravo"),
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            Some(identity(1).as_slice()),
+            consensus.find_by_nickname("alpha").map(|or| or.identity())
+        );
+        assert_eq!(
+            Some(identity(2).as_slice()),
+            consensus.find_by_nickname("BRAVO").map(|or| or.identity())
+        );
+        assert!(consensus.find_by_nickname("charlie").is_none());
+    }
+
+    #[test]
+    fn finds_a_relay_by_identity() {
+        let consensus = parse_consensus_document(&document_with_relays(2)).unwrap();
+
+        assert_eq!(
+            Some("relay2"),
+            consensus
+                .find_by_identity(&identity(2))
+                .map(|or| or.nickname())
+        );
+        assert!(consensus.find_by_identity(&identity(3)).is_none());
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn round_trips_the_parsed_consensus_through_the_cache() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache_dir = cache_dir.path().to_str().unwrap();
+        let document = document_with_relays(2);
+        let consensus = parse_consensus_document(&document).unwrap();
+
+        cache_parsed_consensus(cache_dir, Flavor::Microdesc, &document, &consensus).await;
+
+        assert_eq!(
+            Some(consensus),
+            get_parsed_consensus_from_cache(cache_dir, Flavor::Microdesc, &document).await
+        );
+        assert_eq!(
+            None,
+            get_parsed_consensus_from_cache(cache_dir, Flavor::Ns, &document).await
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn ignores_a_parsed_consensus_cached_for_another_document() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache_dir = cache_dir.path().to_str().unwrap();
+        let document = document_with_relays(2);
+        let consensus = parse_consensus_document(&document).unwrap();
+
+        cache_parsed_consensus(cache_dir, Flavor::Microdesc, &document, &consensus).await;
+
+        assert_eq!(
+            None,
+            get_parsed_consensus_from_cache(cache_dir, Flavor::Microdesc, &document_with_relays(3))
+                .await
+        );
+    }
+
+    #[test]
+    fn iterates_over_the_relays_with_all_the_flags() {
+        let mut consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard"),
+            Relay::new(2).flags("Guard"),
+            Relay::new(3).flags("Exit"),
+            Relay::new(4).flags("Guard Exit"),
+        ]))
+        .unwrap();
+        // The parser skips onion routers without the Fast flag, so one is made slow afterwards.
+        consensus.onion_routers[1].flags.remove(Flags::FAST);
+
+        let nicknames = |flags: Flags| {
+            consensus
+                .relays_with(flags)
+                .map(|or| or.nickname())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            vec!["relay1", "relay4"],
+            nicknames(Flags::GUARD | Flags::FAST)
+        );
+        assert_eq!(vec!["relay1", "relay2", "relay4"], nicknames(Flags::GUARD));
+        assert_eq!(vec!["relay4"], nicknames(Flags::GUARD | Flags::EXIT));
+    }
+
+    #[test]
+    fn reports_a_relay_with_an_empty_identity() {
+        let mut consensus = parse_consensus_document(&document_with_relays(2)).unwrap();
+        assert!(consensus.check_identities().is_ok());
+
+        // The parser never yields an empty identity, so one is cleared as a misparse would.
+        consensus.onion_routers[1].identity.clear();
+
+        assert!(matches!(
+            consensus.check_identities(),
+            Err(ParseError::EmptyIdentity(nickname)) if nickname == "relay2"
+        ));
+    }
+
+    #[test]
+    fn chooses_in_proportion_to_a_score_favouring_a_nickname() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).nickname("favourite"),
+            Relay::new(2),
+            Relay::new(3),
+            Relay::new(4).nickname("favouriteexit").flags("Exit"),
+        ]))
+        .unwrap();
+        let score = |or: &OnionRouter| {
+            if or.nickname().starts_with("favourite") {
+                8.0
+            } else {
+                1.0
+            }
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let draws = 10_000;
+        let mut counts = HashMap::new();
+        for _ in 0..draws {
+            let or = consensus
+                .choose_weighted_by_with_rng(Flags::empty(), Flags::EXIT, score, &mut rng)
+                .unwrap();
+            *counts.entry(or.nickname()).or_insert(0) += 1;
+        }
+
+        // The exit is forbidden, so the favourite is chosen 8 times out of 10.
+        assert_eq!(None, counts.get("favouriteexit"));
+        let share = counts["favourite"] as f64 / draws as f64;
+        assert!((share - 0.8).abs() < 0.02, "{}", share);
     }
 }
@@ -0,0 +1,165 @@
+use flate2::read::{GzDecoder, ZlibDecoder};
+use std::collections::HashMap;
+use std::io::Read;
+
+/// Decompresses a body encoded with a particular Content-Encoding.
+pub trait Decompressor {
+    fn decompress(&self, bytes: &[u8]) -> Result<Vec<u8>, DecompressError>;
+}
+
+#[derive(Debug)]
+pub enum DecompressError {
+    /// The body isn't validly encoded.
+    Io(std::io::Error),
+    /// No decompressor is registered for the Content-Encoding.
+    UnknownEncoding(String),
+}
+
+/// The decompressors keyed by the Content-Encoding they decode, e.g. `deflate`.
+/// The default ones cover deflate, gzip and zstd.
+pub struct Decompressors(HashMap<String, Box<dyn Decompressor>>);
+
+impl Decompressors {
+    /// A registry without any decompressor.
+    pub fn empty() -> Self {
+        Decompressors(HashMap::new())
+    }
+
+    /// Registers the decompressor for the Content-Encoding, replacing the one registered for it
+    /// before, if any.
+    pub fn register(&mut self, encoding: &str, decompressor: Box<dyn Decompressor>) {
+        self.0.insert(encoding.to_lowercase(), decompressor);
+    }
+
+    /// The Content-Encodings there are decompressors for, e.g. for an Accept-Encoding header.
+    pub fn encodings(&self) -> Vec<&str> {
+        let mut encodings = self.0.keys().map(String::as_str).collect::<Vec<_>>();
+        encodings.sort_unstable();
+        encodings
+    }
+
+    /// Decompresses the body encoded with `encoding`. Without an encoding, e.g. an archived ".z"
+    /// document served as a plain file, the body is decompressed according to its magic bytes,
+    /// or returned as is if it has none.
+    pub fn decompress(&self, encoding: &str, bytes: &[u8]) -> Result<Vec<u8>, DecompressError> {
+        let mut encoding = encoding.trim().to_lowercase();
+        if encoding.is_empty() {
+            encoding = sniff_encoding(bytes).to_string();
+        }
+        if encoding.is_empty() || encoding == "identity" {
+            return Ok(bytes.to_vec());
+        }
+
+        match self.0.get(&encoding) {
+            Some(decompressor) => decompressor.decompress(bytes),
+            None => Err(DecompressError::UnknownEncoding(encoding)),
+        }
+    }
+}
+
+// The encoding the body is in according to its magic bytes, or "" if it has none.
+//
+// https://www.rfc-editor.org/rfc/rfc1950 (zlib)
+//    CMF (Compression Method and flags) ... CM = 8 denotes the "deflate" compression method
+//    with a window size up to 32K. ... CINFO values above 7 are not allowed in this version
+//    ...
+//    The FCHECK value must be such that CMF and FLG, when viewed as a 16-bit unsigned integer
+//    stored in MSB order (CMF*256 + FLG), is a multiple of 31.
+// https://www.rfc-editor.org/rfc/rfc8878 (zstd)
+//    Magic_Number: 4 bytes, little-endian format.  Value: 0xFD2FB528.
+// https://www.rfc-editor.org/rfc/rfc1952 (gzip)
+//    ID1 (IDentification 1) ... ID1 = 31 (0x1f), ID2 = 139 (0x8b)
+fn sniff_encoding(bytes: &[u8]) -> &'static str {
+    match bytes {
+        [0x28, 0xb5, 0x2f, 0xfd, ..] => "zstd",
+        [0x1f, 0x8b, ..] => "gzip",
+        [cmf, flg, ..]
+            if cmf & 0x0f == 8
+                && cmf >> 4 <= 7
+                && (u16::from(*cmf) * 256 + u16::from(*flg)) % 31 == 0 =>
+        {
+            "deflate"
+        }
+        _ => "",
+    }
+}
+
+// https://github.com/torproject/torspec/blob/main/dir-spec.txt
+// 6.1. HTTP headers
+//    ... "deflate", "gzip", "x-zstd", "x-tor-lzma" ...
+impl Default for Decompressors {
+    fn default() -> Self {
+        let mut decompressors = Decompressors::empty();
+        decompressors.register("deflate", Box::new(Deflate));
+        decompressors.register("gzip", Box::new(Gzip));
+        decompressors.register("zstd", Box::new(Zstd));
+        decompressors.register("x-zstd", Box::new(Zstd));
+        decompressors
+    }
+}
+
+/// The zlib format, which HTTP calls deflate. Tor's ".z" documents are in it.
+pub struct Deflate;
+
+impl Decompressor for Deflate {
+    fn decompress(&self, bytes: &[u8]) -> Result<Vec<u8>, DecompressError> {
+        read_all(ZlibDecoder::new(bytes))
+    }
+}
+
+pub struct Gzip;
+
+impl Decompressor for Gzip {
+    fn decompress(&self, bytes: &[u8]) -> Result<Vec<u8>, DecompressError> {
+        read_all(GzDecoder::new(bytes))
+    }
+}
+
+pub struct Zstd;
+
+impl Decompressor for Zstd {
+    fn decompress(&self, bytes: &[u8]) -> Result<Vec<u8>, DecompressError> {
+        zstd::stream::decode_all(bytes).map_err(DecompressError::Io)
+    }
+}
+
+fn read_all<R: Read>(mut decoder: R) -> Result<Vec<u8>, DecompressError> {
+    let mut decompressed = vec![];
+    decoder
+        .read_to_end(&mut decompressed)
+        .map_err(DecompressError::Io)?;
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reverses the bytes.
+    struct Reverse;
+
+    impl Decompressor for Reverse {
+        fn decompress(&self, bytes: &[u8]) -> Result<Vec<u8>, DecompressError> {
+            Ok(bytes.iter().rev().copied().collect())
+        }
+    }
+
+    #[test]
+    fn routes_to_a_registered_decompressor_by_encoding() {
+        let mut decompressors = Decompressors::default();
+        decompressors.register("X-Reverse", Box::new(Reverse));
+
+        assert_eq!(
+            b"consensus".to_vec(),
+            decompressors.decompress("x-reverse", b"susnesnoc").unwrap()
+        );
+        assert_eq!(
+            vec!["deflate", "gzip", "x-reverse", "x-zstd", "zstd"],
+            decompressors.encodings()
+        );
+        assert!(matches!(
+            Decompressors::empty().decompress("x-reverse", b"susnesnoc"),
+            Err(DecompressError::UnknownEncoding(encoding)) if encoding == "x-reverse"
+        ));
+    }
+}
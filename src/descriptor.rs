@@ -0,0 +1,212 @@
+use crate::consensus::{Consensus, Flavor, OnionRouter};
+use crate::transport::{Transport, TransportError};
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+// https://github.com/torproject/torspec/blob/main/dir-spec.txt
+// 2.1.1. Server descriptor format
+//
+// 5.2. Downloading router descriptors or microdescriptors
+//    The server descriptor with (descriptor) digest <D> (in hex) is
+//    available at:
+//      http://<hostname>/tor/server/d/<D>[.z]
+
+/// The fields of a server descriptor that microdescriptors leave out.
+#[derive(Debug)]
+pub struct ServerDescriptor {
+    /// The software the onion router runs, e.g. `Tor 0.4.7.10 on Linux`.
+    pub platform: Option<String>,
+    pub published: DateTime<Utc>,
+    pub bandwidth: DescriptorBandwidth,
+    /// The "accept" and "reject" rules in order, e.g. `reject *:25`. The first one matching an
+    /// address and port applies.
+    pub exit_policy: Vec<String>,
+    pub contact: Option<String>,
+}
+
+/// The bandwidths in bytes per second the onion router reports about itself.
+#[derive(Debug)]
+pub struct DescriptorBandwidth {
+    pub average: u64,
+    pub burst: u64,
+    pub observed: u64,
+}
+
+#[derive(Debug)]
+pub enum DescriptorError {
+    /// The consensus doesn't have the onion router's descriptor digest.
+    MissingDigest(String),
+    Transport(TransportError),
+    /// The fetched server descriptor couldn't be parsed.
+    Parse(String),
+    /// The consensus isn't of the ns flavor, so it doesn't refer to server descriptors.
+    WrongFlavor(Flavor),
+}
+
+impl Consensus {
+    /// Fetches the server descriptor of the onion router, e.g. for its contact info or full exit
+    /// policy, which the microdescriptor doesn't have.
+    pub async fn fetch_server_descriptor<T: Transport>(
+        &self,
+        relay: &OnionRouter,
+        transport: &T,
+    ) -> Result<ServerDescriptor, DescriptorError> {
+        if self.flavor != Flavor::Ns {
+            return Err(DescriptorError::WrongFlavor(self.flavor));
+        }
+        let digest = relay
+            .digest()
+            .ok_or_else(|| DescriptorError::MissingDigest(relay.nickname().to_string()))?;
+        let digest = digest
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<String>();
+        let document = transport
+            .get(&format!("/tor/server/d/{}", digest))
+            .await
+            .map_err(DescriptorError::Transport)?;
+
+        parse_server_descriptor(&document)
+    }
+}
+
+// https://github.com/torproject/torspec/blob/main/dir-spec.txt
+// 2.1.1. Server descriptor format
+//    "bandwidth" bandwidth-avg bandwidth-burst bandwidth-observed NL
+//       [Exactly once]
+//
+//    "platform" string NL
+//       [At most once]
+//
+//    "published" YYYY-MM-DD HH:MM:SS NL
+//       [Exactly once]
+//
+//    "contact" info NL
+//       [At most once]
+//
+//    "accept" exitpattern NL
+//    "reject" exitpattern NL
+//       [Any number]
+fn parse_server_descriptor(document: &str) -> Result<ServerDescriptor, DescriptorError> {
+    let mut platform = None;
+    let mut published = None;
+    let mut bandwidth = None;
+    let mut exit_policy = vec![];
+    let mut contact = None;
+
+    for line in document.lines() {
+        let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match keyword {
+            "platform" => platform = Some(rest.to_string()),
+            "contact" => contact = Some(rest.to_string()),
+            "published" => {
+                let datetime = NaiveDateTime::parse_from_str(rest, "%Y-%m-%d %H:%M:%S")
+                    .map_err(|e| DescriptorError::Parse(format!("{}: {}", line, e)))?;
+                published = Some(DateTime::<Utc>::from_utc(datetime, Utc));
+            }
+            "bandwidth" => {
+                let values = rest
+                    .split_whitespace()
+                    .map(str::parse)
+                    .collect::<Result<Vec<u64>, _>>()
+                    .map_err(|e| DescriptorError::Parse(format!("{}: {}", line, e)))?;
+                match values.as_slice() {
+                    [average, burst, observed] => {
+                        bandwidth = Some(DescriptorBandwidth {
+                            average: *average,
+                            burst: *burst,
+                            observed: *observed,
+                        })
+                    }
+                    _ => return Err(DescriptorError::Parse(line.to_string())),
+                }
+            }
+            "accept" | "reject" => exit_policy.push(line.to_string()),
+            _ => {
+                // TODO
+            }
+        }
+    }
+
+    Ok(ServerDescriptor {
+        platform,
+        published: published
+            .ok_or_else(|| DescriptorError::Parse("missing published".to_string()))?,
+        bandwidth: bandwidth
+            .ok_or_else(|| DescriptorError::Parse("missing bandwidth".to_string()))?,
+        exit_policy,
+        contact,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::parse_consensus_document;
+    use crate::testing::*;
+
+    const SERVER_DESCRIPTOR: &str = "router relay1 10.1.0.1 9001 0 0\n\
+         platform Tor 0.4.7.10 on Linux\n\
+         published 2022-10-01 00:00:00\n\
+         bandwidth 1073741824 1073741824 2097152\n\
+         contact relay1 <operator AT example dot com>\n\
+         reject *:25\n\
+         accept *:*\n\
+         router-signature\n";
+
+    #[test]
+    fn parses_a_server_descriptor() {
+        let descriptor = parse_server_descriptor(SERVER_DESCRIPTOR).unwrap();
+
+        assert_eq!(
+            Some("Tor 0.4.7.10 on Linux".to_string()),
+            descriptor.platform
+        );
+        assert_eq!(datetime("2022-10-01 00:00:00"), descriptor.published);
+        assert_eq!(1073741824, descriptor.bandwidth.average);
+        assert_eq!(1073741824, descriptor.bandwidth.burst);
+        assert_eq!(2097152, descriptor.bandwidth.observed);
+        assert_eq!(vec!["reject *:25", "accept *:*"], descriptor.exit_policy);
+        assert_eq!(
+            Some("relay1 <operator AT example dot com>".to_string()),
+            descriptor.contact
+        );
+    }
+
+    #[test]
+    fn fails_to_parse_a_server_descriptor_without_bandwidth() {
+        assert!(matches!(
+            parse_server_descriptor("published 2022-10-01 00:00:00\n"),
+            Err(DescriptorError::Parse(message)) if message == "missing bandwidth"
+        ));
+    }
+
+    #[tokio::test]
+    async fn fetches_the_server_descriptor_by_its_digest() {
+        let consensus =
+            parse_consensus_document(&document_of_flavor(Flavor::Ns, &[Relay::new(1)])).unwrap();
+        let path = format!("/tor/server/d/{}", "D0".repeat(20));
+        let transport = MockTransport::new().with(&path, SERVER_DESCRIPTOR);
+
+        let descriptor = consensus
+            .fetch_server_descriptor(&consensus.onion_routers[0], &transport)
+            .await
+            .unwrap();
+
+        assert_eq!(vec!["reject *:25", "accept *:*"], descriptor.exit_policy);
+        assert_eq!(vec![path], *transport.requests.borrow());
+    }
+
+    #[tokio::test]
+    async fn doesnt_fetch_a_server_descriptor_with_a_microdesc_consensus() {
+        let consensus = parse_consensus_document(&document(&[Relay::new(1)])).unwrap();
+        let transport = MockTransport::new();
+
+        assert!(matches!(
+            consensus
+                .fetch_server_descriptor(&consensus.onion_routers[0], &transport)
+                .await,
+            Err(DescriptorError::WrongFlavor(Flavor::Microdesc))
+        ));
+        assert!(transport.requests.borrow().is_empty());
+    }
+}
@@ -0,0 +1,666 @@
+use crate::authority::DirectoryAuthority;
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::consensus::{
+    cache_consensus_document, get_consensus_document_from_cache, parse_consensus_document,
+    Consensus, ConsensusDiff, Flavor, ParseError,
+};
+#[cfg(feature = "serde")]
+use crate::consensus::{cache_parsed_consensus, get_parsed_consensus_from_cache};
+use crate::transport::{HttpTransport, Transport, TransportError};
+use chrono::{DateTime, Duration, Utc};
+use std::path::PathBuf;
+
+// Cache key of the time until which downloading is backed off after every authority failed.
+const CACHE_KEY_UNREACHABLE_UNTIL: &str = "authorities_unreachable_until";
+// How long to back off after every authority failed, so that immediately running the command
+// again doesn't hammer authorities that are down.
+const UNREACHABLE_BACKOFF_SECONDS: i64 = 60;
+// How long to wait before retrying a failed download against the same authority.
+const RETRY_DELAY_MILLIS: u64 = 500;
+
+/// The path to the current consensus on a directory server.
+//
+// https://github.com/torproject/torspec/blob/main/dir-spec.txt
+//    The most recent v3 consensus should be available at:
+//       http://<hostname>/tor/status-vote/current/consensus[.z]
+//
+//    Similarly, the v3 microdescriptor consensus should be available at:
+//     http://<hostname>/tor/status-vote/current/consensus-microdesc[.z]
+//
+// Note: A .z URL is a compressed versions of the consensus.
+//
+// https://github.com/torproject/torspec/blob/main/dir-spec.txt
+//    Microdescriptors are a stripped-down version of server descriptors
+//    generated by the directory authorities which may additionally contain
+//    authority-generated information.  Microdescriptors contain only the
+//    most relevant parts that clients care about.  Microdescriptors are
+//    expected to be relatively static and only change about once per week.
+//    Microdescriptors do not contain any information that clients need to
+//    use to decide which servers to fetch information about, or which
+//    servers to fetch information from.
+pub(crate) fn consensus_path(flavor: Flavor, compressed: bool) -> String {
+    format!(
+        "/tor/status-vote/current/consensus{}{}",
+        match flavor {
+            Flavor::Ns => "",
+            Flavor::Microdesc => "-microdesc",
+        },
+        if compressed { ".z" } else { "" }
+    )
+}
+
+#[derive(Debug)]
+pub enum GetConsensusError {
+    Transport(TransportError),
+    Parse(ParseError),
+    /// Every authority failed recently, so no download is attempted until the time.
+    BackingOff(DateTime<Utc>),
+    /// Every authority failed, with the error of each by its name.
+    AllAuthoritiesFailed(Vec<(String, GetConsensusError)>),
+    /// There is no valid cached consensus, and downloading is ruled out by `Config::offline`.
+    Offline,
+}
+
+/// Gets the consensus, going through the cache:
+/// * A cached consensus that is still fresh is returned as is.
+/// * A cached consensus that is no longer fresh but still valid is revalidated with a conditional
+///   request, and returned if the directory server has nothing newer.
+/// * Otherwise a new consensus is downloaded. A failed download is retried up to
+///   `config.retries_per_authority` times before giving up on the authority.
+///
+/// With `config.offline`, the cached consensus is returned without revalidation, and `transport`
+/// is never used.
+///
+/// `authority` is the name of the directory authority `transport` fetches from.
+//
+// https://github.com/torproject/torspec/blob/main/dir-spec.txt
+// 5.1. Downloading network-status documents
+//    A client should not download a new consensus while its current one is still fresh.
+pub async fn get_consensus<C: Clock, T: Transport>(
+    config: &Config,
+    authority: &str,
+    clock: &C,
+    transport: &T,
+) -> Result<Consensus, GetConsensusError> {
+    get_consensus_with_diff(config, authority, clock, transport)
+        .await
+        .map(|(consensus, _)| consensus)
+}
+
+/// Same as `get_consensus`, but also returns how the relays changed when revalidation replaced
+/// the cached consensus with a newer one.
+pub async fn get_consensus_with_diff<C: Clock, T: Transport>(
+    config: &Config,
+    authority: &str,
+    clock: &C,
+    transport: &T,
+) -> Result<(Consensus, Option<ConsensusDiff>), GetConsensusError> {
+    let now = clock.now();
+    let path = consensus_path(config.flavor, config.compressed);
+
+    if let Some((cached, cached_from)) = get_cached_consensus(config, &now).await? {
+        if now <= cached.fresh_until {
+            println!(
+                "Using cached consensus document from {}.",
+                cached_from.as_deref().unwrap_or("an unknown authority")
+            );
+            return Ok((cached, None));
+        }
+        if config.offline {
+            println!("Using cached consensus document without revalidating it (offline).");
+            return Ok((cached, None));
+        }
+
+        println!("Revalidating cached consensus document.");
+        return match transport
+            .get_if_modified_since(&path, &cached.valid_after)
+            .await
+        {
+            Ok(Some(document)) => {
+                let consensus = parse_and_cache_consensus(config, authority, &document).await?;
+                let diff = cached.diff(&consensus);
+                Ok((consensus, Some(diff)))
+            }
+            Ok(None) => Ok((cached, None)),
+            // The cached consensus is still valid, so it can be used for now.
+            Err(e) => {
+                println!("Failed to revalidate cached consensus document: {:?}", e);
+                Ok((cached, None))
+            }
+        };
+    }
+
+    if config.offline {
+        return Err(GetConsensusError::Offline);
+    }
+
+    println!("Downloading consensus document.");
+    let mut downloaded = download_consensus(config, authority, transport, &path).await;
+    for _ in 0..config.retries_per_authority {
+        match &downloaded {
+            Ok(_) => break,
+            Err(e) => println!("Failed to download consensus document, retrying: {:?}", e),
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(RETRY_DELAY_MILLIS)).await;
+        downloaded = download_consensus(config, authority, transport, &path).await;
+    }
+    let document = downloaded.map_err(GetConsensusError::Transport)?;
+    let consensus = parse_and_cache_consensus(config, authority, &document).await?;
+    Ok((consensus, None))
+}
+
+/// Gets the consensus from the directory authorities in turn (see `get_consensus`), until one of
+/// them provides it. `transport` makes the transport to fetch from an authority, or returns `None`
+/// to pass over it, e.g. when it has no dir port.
+///
+/// Once every authority has failed, no download is attempted for `UNREACHABLE_BACKOFF_SECONDS`.
+///
+/// With `config.offline`, the cached consensus is returned without trying any authority.
+///
+/// The relay diff is returned as by `get_consensus_with_diff`.
+pub async fn get_consensus_from_authorities<C, T, F>(
+    config: &Config,
+    authorities: &[DirectoryAuthority],
+    clock: &C,
+    transport: F,
+) -> Result<(Consensus, Option<ConsensusDiff>), GetConsensusError>
+where
+    C: Clock,
+    T: Transport,
+    F: Fn(&DirectoryAuthority) -> Option<T>,
+{
+    let now = clock.now();
+    if config.offline {
+        return match get_cached_consensus(config, &now).await? {
+            Some((consensus, _)) => {
+                println!("Using cached consensus document without revalidating it (offline).");
+                Ok((consensus, None))
+            }
+            None => Err(GetConsensusError::Offline),
+        };
+    }
+    if let Some(until) = unreachable_until(&config.cache_dir).await {
+        if now < until {
+            return Err(GetConsensusError::BackingOff(until));
+        }
+    }
+
+    let mut failures = vec![];
+    for da in authorities {
+        let transport = match transport(da) {
+            Some(transport) => transport,
+            None => continue,
+        };
+        match get_consensus_with_diff(config, &da.name, clock, &transport).await {
+            Ok(found) => return Ok(found),
+            Err(e) => failures.push((da.name.clone(), e)),
+        }
+    }
+
+    let until = now + Duration::seconds(UNREACHABLE_BACKOFF_SECONDS);
+    cacache::write(
+        &config.cache_dir,
+        CACHE_KEY_UNREACHABLE_UNTIL,
+        until.to_rfc3339(),
+    )
+    .await
+    .unwrap();
+    Err(GetConsensusError::AllAuthoritiesFailed(failures))
+}
+
+// The cached consensus if it is still valid at `now`, along with the authority it was downloaded
+// from.
+async fn get_cached_consensus(
+    config: &Config,
+    now: &DateTime<Utc>,
+) -> Result<Option<(Consensus, Option<String>)>, GetConsensusError> {
+    let document =
+        match get_consensus_document_from_cache(&config.cache_dir, config.flavor, now).await {
+            Some(document) => document,
+            None => return Ok(None),
+        };
+    // The parsed consensus is cached along with the document, so that it isn't parsed again.
+    #[cfg(feature = "serde")]
+    let parsed =
+        get_parsed_consensus_from_cache(&config.cache_dir, config.flavor, &document.body).await;
+    #[cfg(not(feature = "serde"))]
+    let parsed = None;
+    let consensus = match parsed {
+        Some(consensus) => consensus,
+        None => parse_consensus_document(&document.body).map_err(GetConsensusError::Parse)?,
+    };
+    Ok(Some((consensus, document.authority)))
+}
+
+async fn download_consensus<T: Transport>(
+    config: &Config,
+    authority: &str,
+    transport: &T,
+    path: &str,
+) -> Result<String, TransportError> {
+    transport
+        .get_resumable(
+            path,
+            &partial_download_path(authority, config.flavor, config.compressed),
+        )
+        .await
+}
+
+// The time until which downloading is backed off, if every authority has failed.
+async fn unreachable_until(cache_dir: &str) -> Option<DateTime<Utc>> {
+    let until = cacache::read(cache_dir, CACHE_KEY_UNREACHABLE_UNTIL)
+        .await
+        .ok()?;
+    DateTime::parse_from_rfc3339(&String::from_utf8(until).ok()?)
+        .ok()
+        .map(|until| until.with_timezone(&Utc))
+}
+
+/// Downloads the consensus at `url`, e.g. an archived one, bypassing the cache.
+/// A compressed document is decompressed according to its Content-Encoding, or to its magic bytes
+/// if the server doesn't set one, as for an archived ".z" file.
+pub async fn get_consensus_from_url(
+    client: reqwest::Client,
+    url: &str,
+) -> Result<Consensus, GetConsensusError> {
+    // The whole URL serves as the base URL of an empty path.
+    let document = HttpTransport::new(url.to_string(), client)
+        .get("")
+        .await
+        .map_err(GetConsensusError::Transport)?;
+    parse_consensus_document(&document).map_err(GetConsensusError::Parse)
+}
+
+// The file a download of the consensus from the authority is saved to until it is complete. The
+// compressed and uncompressed documents are saved apart since their bytes don't line up.
+fn partial_download_path(authority: &str, flavor: Flavor, compressed: bool) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "gantz_consensus_{}_{}{}.partial",
+        authority,
+        flavor.name(),
+        if compressed { ".z" } else { "" }
+    ))
+}
+
+async fn parse_and_cache_consensus(
+    config: &Config,
+    authority: &str,
+    document: &str,
+) -> Result<Consensus, GetConsensusError> {
+    let consensus = parse_consensus_document(document).map_err(GetConsensusError::Parse)?;
+    cache_consensus_document(
+        &config.cache_dir,
+        config.flavor,
+        document,
+        authority,
+        &consensus.valid_until,
+        config.cache_size_cap,
+    )
+    .await;
+    #[cfg(feature = "serde")]
+    cache_parsed_consensus(&config.cache_dir, config.flavor, document, &consensus).await;
+
+    Ok(consensus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authority::directory_authorities;
+    use crate::clock::FixedClock;
+    use crate::testing::*;
+    use std::cell::RefCell;
+
+    fn names(authorities: &[DirectoryAuthority]) -> Vec<String> {
+        authorities.iter().map(|da| da.name.clone()).collect()
+    }
+
+    #[test]
+    fn appends_the_z_suffix_when_compressed() {
+        assert_eq!(
+            "/tor/status-vote/current/consensus-microdesc.z",
+            consensus_path(Flavor::Microdesc, true)
+        );
+        assert_eq!(
+            "/tor/status-vote/current/consensus-microdesc",
+            consensus_path(Flavor::Microdesc, false)
+        );
+        assert_eq!(
+            "/tor/status-vote/current/consensus.z",
+            consensus_path(Flavor::Ns, true)
+        );
+        assert_eq!(
+            "/tor/status-vote/current/consensus",
+            consensus_path(Flavor::Ns, false)
+        );
+    }
+
+    // The path of the consensus `config` downloads.
+    fn path() -> String {
+        consensus_path(Flavor::Microdesc, true)
+    }
+
+    // Downloads `document` into the cache at valid-after.
+    async fn cache(config: &Config, document: &str) {
+        let transport = MockTransport::new().with(&path(), document);
+        get_consensus(
+            config,
+            "moria1",
+            &FixedClock(datetime(VALID_AFTER)),
+            &transport,
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn uses_a_fresh_cached_consensus() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let config = config(cache_dir.path());
+        cache(&config, &document_with_relays(1)).await;
+        let clock = FixedClock(datetime(FRESH_UNTIL) - Duration::minutes(1));
+        let transport = MockTransport::new();
+
+        let consensus = get_consensus(&config, "moria1", &clock, &transport)
+            .await
+            .unwrap();
+
+        assert_eq!(1, consensus.relay_count());
+        assert!(transport.requests.borrow().is_empty());
+    }
+
+    #[tokio::test]
+    async fn revalidates_a_cached_consensus_that_isnt_fresh() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let config = config(cache_dir.path());
+        cache(&config, &document_with_relays(1)).await;
+        let clock = FixedClock(datetime(FRESH_UNTIL) + Duration::minutes(1));
+        let transport = MockTransport::new().not_modified();
+
+        let consensus = get_consensus(&config, "moria1", &clock, &transport)
+            .await
+            .unwrap();
+
+        assert_eq!(1, consensus.relay_count());
+        assert_eq!(vec![path()], *transport.requests.borrow());
+    }
+
+    #[tokio::test]
+    async fn downloads_when_the_cached_consensus_expired() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let config = config(cache_dir.path());
+        cache(&config, &document_with_relays(1)).await;
+        let clock = FixedClock(datetime(VALID_UNTIL) + Duration::minutes(1));
+        let transport = MockTransport::new().with(&path(), &document_with_relays(2));
+
+        let consensus = get_consensus(&config, "moria1", &clock, &transport)
+            .await
+            .unwrap();
+
+        assert_eq!(2, consensus.relay_count());
+        assert_eq!(vec![path()], *transport.requests.borrow());
+    }
+
+    #[tokio::test]
+    async fn backs_off_after_every_authority_failed() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let config = config(cache_dir.path());
+        let authorities = &directory_authorities()[..2];
+        let failed_at = datetime(VALID_AFTER);
+        let tried = RefCell::new(vec![]);
+        // Nothing is served, so every download fails.
+        let transport = |da: &DirectoryAuthority| {
+            tried.borrow_mut().push(da.name.clone());
+            Some(MockTransport::new())
+        };
+
+        let failures = match get_consensus_from_authorities(
+            &config,
+            authorities,
+            &FixedClock(failed_at),
+            transport,
+        )
+        .await
+        {
+            Err(GetConsensusError::AllAuthoritiesFailed(failures)) => failures,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(names(authorities), *tried.borrow());
+        assert_eq!(2, failures.len());
+
+        let until = failed_at + Duration::seconds(UNREACHABLE_BACKOFF_SECONDS);
+        let clock = FixedClock(until - Duration::seconds(1));
+        assert!(matches!(
+            get_consensus_from_authorities(&config, authorities, &clock, transport).await,
+            Err(GetConsensusError::BackingOff(t)) if t == until
+        ));
+        assert_eq!(2, tried.borrow().len());
+
+        // They are tried again once the backoff is over.
+        let clock = FixedClock(until);
+        assert!(
+            get_consensus_from_authorities(&config, authorities, &clock, transport)
+                .await
+                .is_err()
+        );
+        assert_eq!(4, tried.borrow().len());
+    }
+
+    #[tokio::test]
+    async fn doesnt_back_off_when_an_authority_provides_the_consensus() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let config = config(cache_dir.path());
+        let authorities = &directory_authorities()[..2];
+        // Only the second authority serves the consensus.
+        let transport = |da: &DirectoryAuthority| {
+            Some(if da.name == authorities[1].name {
+                MockTransport::new().with(&path(), &document_with_relays(1))
+            } else {
+                MockTransport::new()
+            })
+        };
+
+        let (consensus, _) = get_consensus_from_authorities(
+            &config,
+            authorities,
+            &FixedClock(datetime(VALID_AFTER)),
+            transport,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(1, consensus.relay_count());
+        assert_eq!(None, unreachable_until(&config.cache_dir).await);
+    }
+
+    #[tokio::test]
+    async fn decompresses_an_archived_document_served_without_an_encoding() {
+        let document = document_with_relays(2);
+        let mut deflate = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::default());
+        std::io::Write::write_all(&mut deflate, document.as_bytes()).unwrap();
+        let deflate = deflate.finish().unwrap();
+        let zstd = zstd::stream::encode_all(document.as_bytes(), 0).unwrap();
+        let (base_url, _) = serve(vec![
+            http_response("200 OK", &[], &deflate),
+            http_response("200 OK", &[], &zstd),
+            http_response("200 OK", &[], document.as_bytes()),
+        ]);
+        let url = format!(
+            "{}/consensus-microdesc/2022-10-01-00-00-00-consensus.z",
+            base_url
+        );
+
+        for _ in 0..3 {
+            let consensus = get_consensus_from_url(reqwest::Client::new(), &url)
+                .await
+                .unwrap();
+            assert_eq!(2, consensus.relay_count());
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_an_authority_that_failed_once() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let retrying = Config {
+            retries_per_authority: 1,
+            ..config(cache_dir.path())
+        };
+        let clock = FixedClock(datetime(VALID_AFTER));
+        let transport = MockTransport::new()
+            .failing(&path())
+            .with(&path(), &document_with_relays(1));
+
+        let consensus = get_consensus(&retrying, "moria1", &clock, &transport)
+            .await
+            .unwrap();
+
+        assert_eq!(1, consensus.relay_count());
+        assert_eq!(vec![path(), path()], *transport.requests.borrow());
+
+        // Without retries, the failure is final.
+        let cache_dir = tempfile::tempdir().unwrap();
+        let transport = MockTransport::new()
+            .failing(&path())
+            .with(&path(), &document_with_relays(1));
+        assert!(matches!(
+            get_consensus(&config(cache_dir.path()), "moria1", &clock, &transport).await,
+            Err(GetConsensusError::Transport(TransportError::Status(500)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn fails_offline_without_a_cached_consensus() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let offline = Config {
+            offline: true,
+            ..config(cache_dir.path())
+        };
+        let clock = FixedClock(datetime(VALID_AFTER));
+        let transport = MockTransport::new().with(&path(), &document_with_relays(1));
+
+        assert!(matches!(
+            get_consensus(&offline, "moria1", &clock, &transport).await,
+            Err(GetConsensusError::Offline)
+        ));
+        assert!(transport.requests.borrow().is_empty());
+
+        // An expired one isn't used either.
+        cache(&config(cache_dir.path()), &document_with_relays(1)).await;
+        let clock = FixedClock(datetime(VALID_UNTIL) + Duration::minutes(1));
+        assert!(matches!(
+            get_consensus(&offline, "moria1", &clock, &transport).await,
+            Err(GetConsensusError::Offline)
+        ));
+        assert!(transport.requests.borrow().is_empty());
+    }
+
+    #[tokio::test]
+    async fn tries_no_authority_offline() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let offline = Config {
+            offline: true,
+            ..config(cache_dir.path())
+        };
+        let authorities = directory_authorities();
+        let tried = RefCell::new(vec![]);
+        let transport = |da: &DirectoryAuthority| {
+            tried.borrow_mut().push(da.name.clone());
+            Some(MockTransport::new().with(&path(), &document_with_relays(1)))
+        };
+        let clock = FixedClock(datetime(VALID_AFTER));
+
+        assert!(matches!(
+            get_consensus_from_authorities(&offline, &authorities, &clock, transport).await,
+            Err(GetConsensusError::Offline)
+        ));
+
+        cache(&config(cache_dir.path()), &document_with_relays(1)).await;
+        let clock = FixedClock(datetime(VALID_UNTIL) - Duration::minutes(1));
+        let (consensus, _) =
+            get_consensus_from_authorities(&offline, &authorities, &clock, transport)
+                .await
+                .unwrap();
+        assert_eq!(1, consensus.relay_count());
+        assert!(tried.borrow().is_empty());
+    }
+
+    #[tokio::test]
+    async fn retries_a_timed_out_and_a_failed_download_against_a_server() {
+        let document = document_with_relays(1);
+        let (base_url, requests) = serve(vec![
+            http_response("500 Internal Server Error", &[], b""),
+            http_response("200 OK", &[], document.as_bytes()),
+        ]);
+        // The first connection is accepted but never responded to.
+        let stalling = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stalling_url = format!("http://{}", stalling.local_addr().unwrap());
+        let cache_dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            retries_per_authority: 1,
+            ..config(cache_dir.path())
+        };
+        let clock = FixedClock(datetime(VALID_AFTER));
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_millis(200))
+            .build()
+            .unwrap();
+
+        let stalled = get_consensus(
+            &config,
+            "moria1",
+            &clock,
+            &HttpTransport::new(stalling_url, client.clone()),
+        )
+        .await;
+        assert!(matches!(
+            stalled,
+            Err(GetConsensusError::Transport(TransportError::Http(e))) if e.is_timeout()
+        ));
+
+        let consensus = get_consensus(
+            &config,
+            "tor26",
+            &clock,
+            &HttpTransport::new(base_url, client),
+        )
+        .await
+        .unwrap();
+        assert_eq!(1, consensus.relay_count());
+        assert_eq!(2, requests.join().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn replaces_a_cached_consensus_that_changed_on_revalidation() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let config = config(cache_dir.path());
+        let cached = document(&[Relay::new(1), Relay::new(2)]);
+        cache(&config, &cached).await;
+        let clock = FixedClock(datetime(FRESH_UNTIL) + Duration::minutes(1));
+        let changed = document(&[Relay::new(2).flags("Guard"), Relay::new(3)]);
+        let transport = MockTransport::new().with(&path(), &changed);
+
+        let (consensus, diff) = get_consensus_with_diff(&config, "moria1", &clock, &transport)
+            .await
+            .unwrap();
+
+        assert_eq!(parse_consensus_document(&changed).unwrap(), consensus);
+        let diff = diff.unwrap();
+        assert_eq!(
+            ConsensusDiff {
+                added: 1,
+                removed: 1,
+                flags_changed: 1,
+            },
+            diff
+        );
+        assert_eq!("1 added, 1 removed, 1 reflagged", diff.to_string());
+
+        // An unchanged one has no diff.
+        let transport = MockTransport::new().not_modified();
+        let (_, diff) = get_consensus_with_diff(&config, "moria1", &clock, &transport)
+            .await
+            .unwrap();
+        assert_eq!(None, diff);
+    }
+}
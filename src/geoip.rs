@@ -0,0 +1,87 @@
+use crate::consensus::{Consensus, Flags, OnionRouter, SelectionOptions};
+use crate::path::Position;
+use std::net::Ipv4Addr;
+
+/// Locates IPv4 addresses, e.g. backed by a GeoIP database such as Tor's `geoip` file.
+pub trait GeoIpResolver {
+    /// The ISO 3166-1 alpha-2 country code of the address, e.g. `DE`, if it is known.
+    fn country(&self, ip: Ipv4Addr) -> Option<String>;
+}
+
+impl Consensus {
+    /// Chooses a guard relay located in the country `cc`, e.g. `DE`, according to `resolver`.
+    /// Weighted by consensus weight like `choose_guard_relay`.
+    pub fn choose_guard_in_country<R: GeoIpResolver>(
+        &self,
+        cc: &str,
+        resolver: &R,
+        options: &SelectionOptions,
+    ) -> Result<&OnionRouter, String> {
+        let candidates = self
+            .candidates(Flags::GUARD, options)
+            .into_iter()
+            .filter(|or| {
+                resolver
+                    .country(or.ip())
+                    .is_some_and(|country| country.eq_ignore_ascii_case(cc))
+            })
+            .collect::<Vec<_>>();
+
+        self.choose_by_weight(&candidates, Position::Guard)
+            .ok_or_else(|| format!("No guard relay available in {}", cc))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::parse_consensus_document;
+    use crate::testing::*;
+
+    // Locates relay 1 in Germany and relay 2 in the United States, see `Relay::new`.
+    struct StubResolver;
+
+    impl GeoIpResolver for StubResolver {
+        fn country(&self, ip: Ipv4Addr) -> Option<String> {
+            match ip.octets()[1] {
+                1 => Some("DE".to_string()),
+                2 => Some("US".to_string()),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn chooses_a_guard_in_the_country() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard"),
+            Relay::new(2).flags("Guard"),
+            Relay::new(3).flags("Guard"),
+        ]))
+        .unwrap();
+
+        for _ in 0..10 {
+            let guard = consensus
+                .choose_guard_in_country("de", &StubResolver, &SelectionOptions::default())
+                .unwrap();
+            assert_eq!("relay1", guard.nickname());
+        }
+    }
+
+    #[test]
+    fn fails_without_a_guard_in_the_country() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1),
+            Relay::new(2).flags("Guard"),
+            Relay::new(3).flags("Guard"),
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            Err("No guard relay available in DE".to_string()),
+            consensus
+                .choose_guard_in_country("DE", &StubResolver, &SelectionOptions::default())
+                .map(|or| or.nickname())
+        );
+    }
+}
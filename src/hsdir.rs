@@ -0,0 +1,154 @@
+use crate::consensus::{Consensus, Flags, OnionRouter};
+use sha3::{Digest, Sha3_256};
+
+// https://github.com/torproject/torspec/blob/main/rend-spec-v3.txt
+// 2.2.3. Locating, uploading, and downloading hidden service descriptors
+//    hsdir_n_replicas = an integer in range [1,16] with default value 2.
+//    hsdir_spread_fetch = an integer in range [1,128] with default value 3.
+//    hsdir_spread_store = an integer in range [1,128] with default value 4.
+const HSDIR_SPREAD_STORE: usize = 4;
+// 2.2.1. Deriving blinded keys and subcredentials
+//    The time period length is 1440 minutes (one day) by default.
+const TIME_PERIOD_LENGTH_MINUTES: i64 = 1440;
+// The time periods are offset by 12 hours so that they start at 12:00 UTC.
+const TIME_PERIOD_ROTATION_OFFSET_MINUTES: i64 = 12 * 60;
+
+impl Consensus {
+    /// The HSDirs responsible for the onion service descriptor with the blinded key, for the time
+    /// period the consensus was made in.
+    ///
+    /// Only HSDirs whose Ed25519 identity is known (see `resolve_hsdir_identities`) are placed on
+    /// the hash ring. Returns an empty list if the consensus has no shared random value.
+    //
+    // https://github.com/torproject/torspec/blob/main/rend-spec-v3.txt
+    // 2.2.3. Locating, uploading, and downloading hidden service descriptors
+    //    For each replicanum in [1, hsdir_n_replicas], the hidden service will compute
+    //    its index, and then pick the hsdir_spread_store nodes that follow that index
+    //    in the hash ring, skipping nodes that have already been picked.
+    pub fn responsible_hsdirs(&self, blinded_key: &[u8], replicas: usize) -> Vec<&OnionRouter> {
+        let shared_random_value = match &self.shared_random_value {
+            Some(srv) => srv,
+            None => return vec![],
+        };
+        let period_num = self.time_period_num();
+
+        let mut ring = self
+            .onion_routers
+            .iter()
+            .filter(|or| or.flags().contains(Flags::HS_DIR))
+            .filter_map(|or| {
+                or.ed25519_identity()
+                    .map(|id| (hsdir_index(id, shared_random_value, period_num), or))
+            })
+            .collect::<Vec<_>>();
+        ring.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut responsible: Vec<&OnionRouter> = vec![];
+        for replica in 1..=replicas {
+            let index = hs_index(blinded_key, replica as u64, period_num);
+            let start = ring.partition_point(|(node_index, _)| *node_index < index);
+            let mut picked = 0;
+            for i in 0..ring.len() {
+                if picked == HSDIR_SPREAD_STORE {
+                    break;
+                }
+                let (_, or) = ring[(start + i) % ring.len()];
+                if !responsible.iter().any(|r| std::ptr::eq(*r, or)) {
+                    responsible.push(or);
+                    picked += 1;
+                }
+            }
+        }
+
+        responsible
+    }
+
+    // The number of the time period valid-after falls in.
+    fn time_period_num(&self) -> u64 {
+        let minutes = self.valid_after.timestamp() / 60 - TIME_PERIOD_ROTATION_OFFSET_MINUTES;
+        (minutes / TIME_PERIOD_LENGTH_MINUTES) as u64
+    }
+}
+
+// hs_index(replicanum) = H("store-at-idx" |
+//                          blinded_public_key |
+//                          INT_8(replicanum) |
+//                          INT_8(period_length) |
+//                          INT_8(period_num) )
+fn hs_index(blinded_key: &[u8], replica: u64, period_num: u64) -> Vec<u8> {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"store-at-idx");
+    hasher.update(blinded_key);
+    hasher.update(replica.to_be_bytes());
+    hasher.update((TIME_PERIOD_LENGTH_MINUTES as u64).to_be_bytes());
+    hasher.update(period_num.to_be_bytes());
+    hasher.finalize().to_vec()
+}
+
+// hsdir_index(node) = H("node-idx" | node_identity |
+//                       shared_random_value |
+//                       INT_8(period_num) |
+//                       INT_8(period_length) )
+fn hsdir_index(ed25519_identity: &[u8], shared_random_value: &[u8], period_num: u64) -> Vec<u8> {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b"node-idx");
+    hasher.update(ed25519_identity);
+    hasher.update(shared_random_value);
+    hasher.update(period_num.to_be_bytes());
+    hasher.update((TIME_PERIOD_LENGTH_MINUTES as u64).to_be_bytes());
+    hasher.finalize().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::consensus::{parse_consensus_document, Consensus};
+    use crate::microdescriptor::microdesc_digest;
+    use crate::testing::*;
+
+    // The nicknames of the HSDirs responsible for a blinded key of 32 7s.
+    fn nicknames(consensus: &Consensus, replicas: usize) -> Vec<String> {
+        consensus
+            .responsible_hsdirs(&[7; 32], replicas)
+            .iter()
+            .map(|or| or.nickname().to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn places_the_hsdirs_resolved_from_their_microdescriptors() {
+        let relays = (1..=8)
+            .map(|id| Relay::new(id).flags("HSDir").line(&m_line(id)))
+            .collect::<Vec<_>>();
+        let document = document(&relays).replace(
+            "dir-source",
+            "shared-rand-current-value 9 QkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkI=\ndir-source",
+        );
+        let mut consensus = parse_consensus_document(&document).unwrap();
+        let digests = (1..=8)
+            .map(|id| microdesc_digest(&microdescriptor(id)))
+            .collect::<Vec<_>>();
+        let transport = MockTransport::new().with(
+            &format!("/tor/micro/d/{}", digests.join("-")),
+            &(1..=8).map(microdescriptor).collect::<String>(),
+        );
+        assert!(nicknames(&consensus, 1).is_empty());
+
+        assert_eq!(
+            8,
+            consensus
+                .resolve_hsdir_identities(&transport)
+                .await
+                .unwrap()
+        );
+
+        // Computed independently from rend-spec-v3 for the time period of valid-after (19265).
+        assert_eq!(
+            vec!["relay1", "relay6", "relay7", "relay3"],
+            nicknames(&consensus, 1)
+        );
+        assert_eq!(
+            vec!["relay1", "relay6", "relay7", "relay3", "relay4", "relay5", "relay8", "relay2"],
+            nicknames(&consensus, 2)
+        );
+    }
+}
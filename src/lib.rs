@@ -0,0 +1,44 @@
+//! Downloads and parses the consensus of the Tor network.
+//!
+//! The consensus parser is exposed for use in other projects, see
+//! `parse_consensus_document`.
+
+// *** Specs ***
+//
+// * Tor directory protocol, version 3 *
+// > 4. Directory cache operation
+// > 5. Client operation
+// https://github.com/torproject/torspec/blob/main/dir-spec.txt
+
+pub mod authority;
+pub mod clock;
+pub mod config;
+mod consensus;
+pub mod decompress;
+mod descriptor;
+pub mod directory;
+pub mod geoip;
+mod hsdir;
+mod microdescriptor;
+mod path;
+mod reachability;
+#[cfg(test)]
+mod testing;
+pub mod transport;
+
+pub use crate::consensus::{
+    parse_consensus_document, parse_consensus_document_from_reader,
+    parse_consensus_document_with_options, parse_consensus_document_with_stats,
+    parse_consensus_documents, parse_consensus_foreach, Consensus, ConsensusDiff, FlagError, Flags,
+    Flavor, NetworkStats, OnionRouter, ParseError, ParseOptions, ParseStats, ReadError,
+    SelectionOptions, SortKey, ValidityError,
+};
+pub use crate::descriptor::{DescriptorBandwidth, DescriptorError, ServerDescriptor};
+pub use crate::microdescriptor::{
+    MicrodescError, Microdescriptor, ResolvedGuard, ResolvedPath, ResolvedRelay,
+};
+pub use crate::path::{Path, PathError, PathPurpose, Position, SelectionError};
+pub use crate::reachability::{
+    order_by_connect_failures, record_relay_connect_result, relay_connect_failures, ProbeError,
+    Prober,
+};
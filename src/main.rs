@@ -1,96 +1,114 @@
-mod consensus;
-
-use crate::consensus::{
-    cache_consensus_document, get_consensus_document_from_cache, parse_consensus_document,
-};
-use chrono::Utc;
-use std::net::Ipv4Addr;
-
-// *** Specs ***
-//
-// * Tor directory protocol, version 3 *
-// > 4. Directory cache operation
-// > 5. Client operation
-// https://github.com/torproject/torspec/blob/main/dir-spec.txt
+use gantz::authority::{directory_authorities, DirectoryAuthority};
+use gantz::clock::{Clock, SystemClock};
+use gantz::config::{Config, OutputFormat};
+use gantz::directory::{get_consensus_from_authorities, get_consensus_from_url};
+use gantz::transport::HttpTransport;
+use rand::seq::SliceRandom;
+use rand::Rng;
 
 #[tokio::main]
 async fn main() {
-    let now = Utc::now();
+    let config = Config::from_env_and_args().unwrap_or_else(|e| panic!("{}", e));
+    let clock = SystemClock;
 
-    let consensus = if let Some(document) = get_consensus_document_from_cache(&now).await {
-        println!("Using cached consensus document.");
-        parse_consensus_document(&document).unwrap()
-    } else {
-        // TODO: Select directory authority randomly.
-        let da = directory_authorities().pop().unwrap();
-        println!("Downloading consensus document from {}", da.consensus_url());
-        // The consensus document is compressed using deflate algorithm.
-        let client = reqwest::Client::builder().deflate(true).build().unwrap();
-        // TODO: error handling
-        let res = client.get(da.consensus_url()).send().await.unwrap();
-        // TODO: error handling
-        let document = res.text().await.unwrap();
-        let consensus = parse_consensus_document(&document).unwrap();
-        cache_consensus_document(&document, &consensus.valid_until).await;
+    // Compressed documents are decompressed by the transport, see `Decompressors`.
+    let mut builder = reqwest::Client::builder();
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy).unwrap());
+    }
+    if let Some(timeout) = config.timeout {
+        builder = builder.timeout(timeout);
+    }
+    let client = builder.build().unwrap();
+
+    // TODO: error handling
+    let consensus = match &config.url {
+        Some(url) => {
+            println!("Using {}", url);
+            get_consensus_from_url(client, url).await.unwrap()
+        }
+        None => {
+            let authorities = match &config.authority {
+                Some(name) => vec![directory_authorities()
+                    .into_iter()
+                    .find(|da| &da.name == name)
+                    .unwrap_or_else(|| panic!("Unknown directory authority: {}", name))],
+                None => shuffle_authorities(directory_authorities(), &mut rand::thread_rng()),
+            };
 
-        consensus
+            let transport = |da: &DirectoryAuthority| {
+                // TODO: Fetch over the OR port (begindir) when the authority has no dir port.
+                let base_url = da.base_url()?;
+                println!("Using directory authority {}", da.name);
+                Some(HttpTransport::new(base_url, client.clone()))
+            };
+            match get_consensus_from_authorities(&config, &authorities, &clock, transport).await {
+                Ok((consensus, diff)) => {
+                    if let Some(diff) = diff {
+                        println!("Relays changed since the cached document: {}.", diff);
+                    }
+                    consensus
+                }
+                Err(e) => {
+                    eprintln!("Failed to get the consensus: {:?}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
     };
 
-    // TODO: error handling
-    assert!(consensus.valid_after <= now && now <= consensus.valid_until);
-    println!("{:?}", consensus);
+    consensus
+        .check_validity_at(&clock.now(), config.skew_tolerance)
+        .unwrap_or_else(|e| panic!("The consensus is not valid: {:?}", e));
+    if consensus.is_truncated() {
+        println!(
+            "Only the first {} onion routers in the consensus were parsed.",
+            consensus.relay_count()
+        );
+    }
+    match config.format {
+        OutputFormat::Debug => println!("{:?}", consensus),
+        OutputFormat::Fingerprint => consensus.write_fingerprints(std::io::stdout()).unwrap(),
+        OutputFormat::Json => consensus.write_json(std::io::stdout()).unwrap(),
+    }
 }
 
-fn directory_authorities() -> Vec<DirectoryAuthority> {
-    // https://consensus-health.torproject.org/
-    vec![
-        DirectoryAuthority::new("maatuska".into(), Ipv4Addr::new(171, 25, 193, 9), 443, 80),
-        // DirectoryAuthority::new("moria1".into(), Ipv4Addr::new(128, 31, 0, 34), 9131, 9101),
-    ]
+// Shuffles the directory authorities so that they are tried in random order, which spreads the
+// load across them.
+fn shuffle_authorities<R: Rng + ?Sized>(
+    mut authorities: Vec<DirectoryAuthority>,
+    rng: &mut R,
+) -> Vec<DirectoryAuthority> {
+    authorities.shuffle(rng);
+    authorities
 }
 
-struct DirectoryAuthority {
-    name: String,
-    ip: Ipv4Addr,
-    dir_port: u32,
-    tor_port: u32,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
 
-impl DirectoryAuthority {
-    fn new(name: String, ip: Ipv4Addr, dir_port: u32, tor_port: u32) -> Self {
-        DirectoryAuthority {
-            name,
-            ip,
-            dir_port,
-            tor_port,
-        }
+    fn names(authorities: &[DirectoryAuthority]) -> Vec<String> {
+        authorities.iter().map(|da| da.name.clone()).collect()
     }
 
-    /// The URL to directory authority's consensus.
-    //
-    // https://github.com/torproject/torspec/blob/main/dir-spec.txt
-    //    The most recent v3 consensus should be available at:
-    //       http://<hostname>/tor/status-vote/current/consensus[.z]
-    //
-    //    Similarly, the v3 microdescriptor consensus should be available at:
-    //     http://<hostname>/tor/status-vote/current/consensus-microdesc[.z]
-    //
-    // Note: A .z URL is a compressed versions of the consensus.
-    //
-    // https://github.com/torproject/torspec/blob/main/dir-spec.txt
-    //    Microdescriptors are a stripped-down version of server descriptors
-    //    generated by the directory authorities which may additionally contain
-    //    authority-generated information.  Microdescriptors contain only the
-    //    most relevant parts that clients care about.  Microdescriptors are
-    //    expected to be relatively static and only change about once per week.
-    //    Microdescriptors do not contain any information that clients need to
-    //    use to decide which servers to fetch information about, or which
-    //    servers to fetch information from.
-    pub(crate) fn consensus_url(&self) -> String {
-        // TODO: https://github.com/servo/rust-url
-        format!(
-            "http://{}:{}/tor/status-vote/current/consensus-microdesc.z",
-            self.ip, self.dir_port
-        )
+    #[test]
+    fn shuffles_all_the_authorities() {
+        let shuffle = |seed| {
+            names(&shuffle_authorities(
+                directory_authorities(),
+                &mut StdRng::seed_from_u64(seed),
+            ))
+        };
+
+        let mut sorted = shuffle(0);
+        sorted.sort();
+        let mut expected = names(&directory_authorities());
+        expected.sort();
+        assert_eq!(expected, sorted);
+        // The same seed yields the same order, and the order varies with the seed.
+        assert_eq!(shuffle(0), shuffle(0));
+        assert!((1..10).any(|seed| shuffle(seed) != shuffle(0)));
     }
 }
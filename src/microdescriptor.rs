@@ -0,0 +1,611 @@
+use crate::consensus::{Consensus, Flags, Flavor, OnionRouter, SelectionOptions};
+use crate::path::Path;
+use crate::transport::{Transport, TransportError};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+
+// https://github.com/torproject/torspec/blob/main/dir-spec.txt
+// 3.3. Computing microdescriptors
+//
+// 5.2. Downloading router descriptors or microdescriptors
+//    Clients try to have the best microdescriptor for each router
+//    listed in the latest microdescriptor consensus they have.
+//    ...
+//    The microdescriptors with base64 hashes <D1>,<D2>,<D3> are available at:
+//      http://<hostname>/tor/micro/d/<D1>-<D2>-<D3>[.z]
+
+// How many microdescriptors are requested at a time, which keeps the URL of a request short.
+const MICRODESCS_PER_REQUEST: usize = 92;
+
+#[derive(Debug)]
+pub struct Microdescriptor {
+    /// The curve25519 key used for the ntor circuit extended handshake.
+    pub ntor_onion_key: Vec<u8>,
+    /// The Ed25519 identity key, if the onion router has one.
+    pub ed25519_identity: Option<Vec<u8>>,
+    /// The onion routers the onion router declares to be in its family, see
+    /// `OnionRouter::is_in_family_with`.
+    pub family: Vec<String>,
+}
+
+/// An onion router along with what is needed to connect to it.
+#[derive(Debug)]
+pub struct ResolvedRelay {
+    pub nickname: String,
+    pub identity: Vec<u8>,
+    pub or_addr: SocketAddr,
+    pub microdescriptor: Microdescriptor,
+}
+
+/// A guard relay chosen and resolved by `Consensus::select_and_resolve_guard`.
+#[derive(Debug)]
+pub struct ResolvedGuard {
+    pub relay: ResolvedRelay,
+    /// Why fetching the microdescriptor from a directory relay failed, if it was fetched from the
+    /// fallback instead.
+    pub directory_error: Option<MicrodescError>,
+}
+
+/// The hops of a path along with what is needed to connect to them.
+#[derive(Debug)]
+pub struct ResolvedPath {
+    pub guard: ResolvedRelay,
+    pub middle: ResolvedRelay,
+    pub exit: ResolvedRelay,
+}
+
+#[derive(Debug)]
+pub enum MicrodescError {
+    /// No onion router could be selected.
+    NoRelay(String),
+    /// The consensus doesn't have the onion router's microdescriptor digest ("m" line).
+    MissingDigest(String),
+    Transport(TransportError),
+    /// The fetched microdescriptor couldn't be parsed.
+    Parse(String),
+    /// The fetched microdescriptor of the onion router doesn't have the digest in the consensus,
+    /// e.g. because a malicious directory relay served other keys.
+    DigestMismatch(String),
+    /// The consensus isn't of the microdesc flavor, so it doesn't refer to microdescriptors.
+    WrongFlavor(Flavor),
+}
+
+impl Consensus {
+    /// Chooses a guard relay and fetches its microdescriptor.
+    /// The microdescriptor is fetched from a directory relay if there is one (see
+    /// `choose_directory_relay`), and from `fallback`, e.g. a directory authority, if there isn't
+    /// or fetching from it fails. `directory_transport` makes the transport to fetch from the
+    /// directory relay with its base URL, e.g. `|base_url| HttpTransport::new(base_url, client)`.
+    pub async fn select_and_resolve_guard<T, D, F>(
+        &self,
+        directory_transport: F,
+        fallback: &T,
+    ) -> Result<ResolvedGuard, MicrodescError>
+    where
+        T: Transport,
+        D: Transport,
+        F: FnOnce(String) -> D,
+    {
+        if self.flavor != Flavor::Microdesc {
+            return Err(MicrodescError::WrongFlavor(self.flavor));
+        }
+        let guard = self
+            .choose_guard_relay(&SelectionOptions::default())
+            .map_err(MicrodescError::NoRelay)?;
+
+        let directory = self
+            .choose_directory_relay(&SelectionOptions::default())
+            .ok()
+            .and_then(|or| or.dir_base_url());
+        let mut directory_error = None;
+        if let Some(base_url) = directory {
+            let transport = directory_transport(base_url);
+            match self.resolve_relay(guard, &transport).await {
+                Ok(relay) => {
+                    return Ok(ResolvedGuard {
+                        relay,
+                        directory_error: None,
+                    })
+                }
+                // The directory relay may be down or serve forged microdescriptors, neither of
+                // which the fallback is expected to.
+                Err(e @ (MicrodescError::Transport(_) | MicrodescError::DigestMismatch(_))) => {
+                    directory_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(ResolvedGuard {
+            relay: self.resolve_relay(guard, fallback).await?,
+            directory_error,
+        })
+    }
+
+    /// Fetches the microdescriptor of the onion router, checking that it has the digest in the
+    /// consensus.
+    pub async fn resolve_relay<T: Transport>(
+        &self,
+        or: &OnionRouter,
+        transport: &T,
+    ) -> Result<ResolvedRelay, MicrodescError> {
+        if self.flavor != Flavor::Microdesc {
+            return Err(MicrodescError::WrongFlavor(self.flavor));
+        }
+        let digest = or
+            .microdesc_digest()
+            .ok_or_else(|| MicrodescError::MissingDigest(or.nickname().to_string()))?;
+        let document = transport
+            .get(&format!("/tor/micro/d/{}", digest))
+            .await
+            .map_err(MicrodescError::Transport)?;
+        if microdesc_digest(&document) != digest.trim_end_matches('=') {
+            return Err(MicrodescError::DigestMismatch(or.nickname().to_string()));
+        }
+
+        Ok(ResolvedRelay {
+            nickname: or.nickname().to_string(),
+            identity: or.identity().to_vec(),
+            or_addr: or.or_socket_addr(),
+            microdescriptor: parse_microdescriptor(&document)?,
+        })
+    }
+
+    /// Fetches the microdescriptors of the HSDirs and sets their Ed25519 identities (see
+    /// `OnionRouter::set_ed25519_identity`), which `responsible_hsdirs` needs to place them on the
+    /// hash ring. Returns the number of HSDirs whose identity was set.
+    pub async fn resolve_hsdir_identities<T: Transport>(
+        &mut self,
+        transport: &T,
+    ) -> Result<usize, MicrodescError> {
+        self.resolve_from_microdescriptors(
+            transport,
+            |or| or.flags().contains(Flags::HS_DIR),
+            |or, microdescriptor| match &microdescriptor.ed25519_identity {
+                Some(key) => {
+                    or.set_ed25519_identity(key.clone());
+                    true
+                }
+                None => false,
+            },
+        )
+        .await
+    }
+
+    /// Fetches the microdescriptors of all the onion routers and sets their families (see
+    /// `OnionRouter::set_family`), which `build_path` needs to keep a family out of a path.
+    /// Returns the number of onion routers whose family was set.
+    pub async fn resolve_families<T: Transport>(
+        &mut self,
+        transport: &T,
+    ) -> Result<usize, MicrodescError> {
+        self.resolve_from_microdescriptors(
+            transport,
+            |_| true,
+            |or, microdescriptor| {
+                or.set_family(microdescriptor.family.clone());
+                true
+            },
+        )
+        .await
+    }
+
+    // Fetches the microdescriptors of the onion routers `filter` returns true for, in batches,
+    // and passes each of the onion routers to `resolve` along with its microdescriptor. Returns
+    // the number of onion routers `resolve` returned true for.
+    async fn resolve_from_microdescriptors<T, P, F>(
+        &mut self,
+        transport: &T,
+        filter: P,
+        mut resolve: F,
+    ) -> Result<usize, MicrodescError>
+    where
+        T: Transport,
+        P: Fn(&OnionRouter) -> bool,
+        F: FnMut(&mut OnionRouter, &Microdescriptor) -> bool,
+    {
+        if self.flavor != Flavor::Microdesc {
+            return Err(MicrodescError::WrongFlavor(self.flavor));
+        }
+        let digests = self
+            .onion_routers
+            .iter()
+            .filter(|or| filter(or))
+            .filter_map(|or| or.microdesc_digest())
+            .map(|digest| digest.trim_end_matches('=').to_string())
+            .collect::<Vec<_>>();
+
+        let mut resolved = 0;
+        for batch in digests.chunks(MICRODESCS_PER_REQUEST) {
+            let document = transport
+                .get(&format!("/tor/micro/d/{}", batch.join("-")))
+                .await
+                .map_err(MicrodescError::Transport)?;
+            for document in split_microdescriptors(&document) {
+                let digest = microdesc_digest(document);
+                let microdescriptor = parse_microdescriptor(document)?;
+                for or in self.onion_routers.iter_mut().filter(|or| {
+                    filter(or)
+                        && or
+                            .microdesc_digest()
+                            .is_some_and(|d| d.trim_end_matches('=') == digest)
+                }) {
+                    if resolve(or, &microdescriptor) {
+                        resolved += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(resolved)
+    }
+}
+
+impl Path<'_> {
+    /// Fetches the microdescriptors of the three hops in a single request.
+    pub async fn resolve_microdescriptors<T: Transport>(
+        &self,
+        transport: &T,
+    ) -> Result<ResolvedPath, MicrodescError> {
+        if self.flavor != Flavor::Microdesc {
+            return Err(MicrodescError::WrongFlavor(self.flavor));
+        }
+        let hops = [self.guard, self.middle, self.exit];
+        let digests = hops
+            .iter()
+            .map(|or| {
+                or.microdesc_digest()
+                    .ok_or_else(|| MicrodescError::MissingDigest(or.nickname().to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let document = transport
+            .get(&format!("/tor/micro/d/{}", digests.join("-")))
+            .await
+            .map_err(MicrodescError::Transport)?;
+
+        // The microdescriptors may be in any order, so they are told apart by their digests.
+        let documents = split_microdescriptors(&document);
+        let mut resolved = hops.iter().zip(digests).map(|(or, digest)| {
+            let document = documents
+                .iter()
+                .find(|d| microdesc_digest(d) == digest.trim_end_matches('='))
+                .ok_or_else(|| {
+                    MicrodescError::Parse(format!(
+                        "missing the microdescriptor of {}",
+                        or.nickname()
+                    ))
+                })?;
+
+            Ok(ResolvedRelay {
+                nickname: or.nickname().to_string(),
+                identity: or.identity().to_vec(),
+                or_addr: or.or_socket_addr(),
+                microdescriptor: parse_microdescriptor(document)?,
+            })
+        });
+
+        Ok(ResolvedPath {
+            guard: resolved.next().unwrap()?,
+            middle: resolved.next().unwrap()?,
+            exit: resolved.next().unwrap()?,
+        })
+    }
+}
+
+// https://github.com/torproject/torspec/blob/main/dir-spec.txt
+// 3.3. Computing microdescriptors
+//    "ntor-onion-key" SP base64-encoded-key NL
+//       [Exactly once]
+//
+//    "family" names NL
+//       [At most once]
+//
+//    "id" SP "ed25519" SP ed25519-identity NL
+//       [At most once per distinct type.]
+fn parse_microdescriptor(document: &str) -> Result<Microdescriptor, MicrodescError> {
+    let mut ntor_onion_key = None;
+    let mut ed25519_identity = None;
+    let mut family = vec![];
+
+    for line in document.lines() {
+        let strs = line.split_whitespace().collect::<Vec<_>>();
+        match strs.as_slice() {
+            ["ntor-onion-key", key] => {
+                ntor_onion_key = Some(decode_base64(key)?);
+            }
+            ["id", "ed25519", key] => {
+                ed25519_identity = Some(decode_base64(key)?);
+            }
+            ["family", names @ ..] => {
+                family = names.iter().map(|name| name.to_string()).collect();
+            }
+            _ => {
+                // TODO
+            }
+        }
+    }
+
+    Ok(Microdescriptor {
+        ntor_onion_key: ntor_onion_key
+            .ok_or_else(|| MicrodescError::Parse("missing ntor-onion-key".to_string()))?,
+        ed25519_identity,
+        family,
+    })
+}
+
+// Splits a response holding several microdescriptors into them, each with the exact text its
+// digest is computed over.
+//
+// https://github.com/torproject/torspec/blob/main/dir-spec.txt
+// 3.3. Computing microdescriptors
+//    "onion-key" NL a public key in PEM format
+//       [At most once]
+//       [No extra arguments]
+//    ...
+//    "ntor-onion-key" SP base64-encoded-key NL
+//       [Exactly once]
+fn split_microdescriptors(document: &str) -> Vec<&str> {
+    let mut documents = vec![];
+    let mut start = 0;
+    let mut offset = 0;
+    let mut has_ntor_onion_key = false;
+
+    for line in document.split_inclusive('\n') {
+        let keyword = line.split_whitespace().next();
+        // "onion-key" is the first line if present, otherwise "ntor-onion-key" is.
+        let starts_new = keyword == Some("onion-key")
+            || (keyword == Some("ntor-onion-key") && has_ntor_onion_key);
+        if starts_new && offset > start {
+            documents.push(&document[start..offset]);
+            start = offset;
+            has_ntor_onion_key = false;
+        }
+        if keyword == Some("ntor-onion-key") {
+            has_ntor_onion_key = true;
+        }
+        offset += line.len();
+    }
+    if offset > start {
+        documents.push(&document[start..offset]);
+    }
+
+    documents
+}
+
+// The base64-encoded SHA256 digest of the microdescriptor, as in the "m" line of the consensus.
+pub(crate) fn microdesc_digest(document: &str) -> String {
+    base64::encode_config(Sha256::digest(document.as_bytes()), base64::STANDARD_NO_PAD)
+}
+
+// Keys in microdescriptors are base64-encoded with or without the trailing "=" signs.
+fn decode_base64(s: &str) -> Result<Vec<u8>, MicrodescError> {
+    base64::decode_config(s.trim_end_matches('='), base64::STANDARD_NO_PAD)
+        .map_err(|e| MicrodescError::Parse(format!("invalid base64 {}: {}", s, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::parse_consensus_document;
+    use crate::testing::*;
+
+    #[tokio::test]
+    async fn resolves_the_guard_from_the_fallback() {
+        let consensus =
+            parse_consensus_document(&document(&[Relay::new(1).flags("Guard").line(&m_line(1))]))
+                .unwrap();
+        let fallback = MockTransport::new().with(&microdescriptor_path(1), &microdescriptor(1));
+
+        let guard = consensus
+            .select_and_resolve_guard(|_| MockTransport::new(), &fallback)
+            .await
+            .unwrap();
+
+        assert_eq!("relay1", guard.relay.nickname);
+        assert_eq!(identity(1), guard.relay.identity);
+        assert_eq!(
+            "10.1.0.1:9001".parse::<SocketAddr>().unwrap(),
+            guard.relay.or_addr
+        );
+        assert_eq!(vec![1; 32], guard.relay.microdescriptor.ntor_onion_key);
+        assert_eq!(
+            Some(vec![1; 32]),
+            guard.relay.microdescriptor.ed25519_identity
+        );
+        assert_eq!(vec![microdescriptor_path(1)], *fallback.requests.borrow());
+    }
+
+    #[tokio::test]
+    async fn prefers_a_directory_relay_to_the_fallback() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard").line(&m_line(1)),
+            Relay::new(2).flags("V2Dir").dir_port(80),
+        ]))
+        .unwrap();
+        let fallback = MockTransport::new();
+        let mut base_urls = vec![];
+
+        let guard = consensus
+            .select_and_resolve_guard(
+                |base_url| {
+                    base_urls.push(base_url);
+                    MockTransport::new().with(&microdescriptor_path(1), &microdescriptor(1))
+                },
+                &fallback,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!("relay1", guard.relay.nickname);
+        assert_eq!(vec!["http://10.2.0.1:80"], base_urls);
+        assert!(fallback.requests.borrow().is_empty());
+    }
+
+    #[tokio::test]
+    async fn falls_back_when_the_directory_relay_fails() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard").line(&m_line(1)),
+            Relay::new(2).flags("V2Dir").dir_port(80),
+        ]))
+        .unwrap();
+        let fallback = MockTransport::new().with(&microdescriptor_path(1), &microdescriptor(1));
+
+        let guard = consensus
+            .select_and_resolve_guard(|_| MockTransport::new(), &fallback)
+            .await
+            .unwrap();
+
+        assert_eq!("relay1", guard.relay.nickname);
+        assert!(matches!(
+            guard.directory_error,
+            Some(MicrodescError::Transport(TransportError::Status(404)))
+        ));
+        assert_eq!(vec![microdescriptor_path(1)], *fallback.requests.borrow());
+    }
+
+    #[tokio::test]
+    async fn rejects_a_forged_microdescriptor_from_the_directory_relay() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard").line(&m_line(1)),
+            Relay::new(2).flags("V2Dir").dir_port(80),
+        ]))
+        .unwrap();
+        // The directory relay serves other keys than those in relay1's microdescriptor.
+        let forging = MockTransport::new().with(&microdescriptor_path(1), &microdescriptor(2));
+        let fallback = MockTransport::new().with(&microdescriptor_path(1), &microdescriptor(1));
+
+        assert!(matches!(
+            consensus
+                .resolve_relay(&consensus.onion_routers()[0], &forging)
+                .await,
+            Err(MicrodescError::DigestMismatch(nickname)) if nickname == "relay1"
+        ));
+        let guard = consensus
+            .select_and_resolve_guard(|_| forging, &fallback)
+            .await
+            .unwrap();
+
+        assert_eq!(vec![1; 32], guard.relay.microdescriptor.ntor_onion_key);
+        assert!(matches!(
+            guard.directory_error,
+            Some(MicrodescError::DigestMismatch(nickname)) if nickname == "relay1"
+        ));
+        assert_eq!(vec![microdescriptor_path(1)], *fallback.requests.borrow());
+    }
+
+    #[tokio::test]
+    async fn refuses_to_fetch_microdescriptors_for_an_ns_consensus() {
+        let mut consensus = parse_consensus_document(&document_of_flavor(
+            Flavor::Ns,
+            &[Relay::new(1).flags("Guard HSDir")],
+        ))
+        .unwrap();
+        let transport = MockTransport::new();
+
+        assert!(matches!(
+            consensus
+                .select_and_resolve_guard(|_| MockTransport::new(), &transport)
+                .await,
+            Err(MicrodescError::WrongFlavor(Flavor::Ns))
+        ));
+        assert!(matches!(
+            consensus.resolve_hsdir_identities(&transport).await,
+            Err(MicrodescError::WrongFlavor(Flavor::Ns))
+        ));
+        let relay = &consensus.onion_routers()[0];
+        assert!(matches!(
+            consensus.resolve_relay(relay, &transport).await,
+            Err(MicrodescError::WrongFlavor(Flavor::Ns))
+        ));
+        let path = Path {
+            guard: relay,
+            middle: relay,
+            exit: relay,
+            flavor: consensus.flavor,
+        };
+        assert!(matches!(
+            path.resolve_microdescriptors(&transport).await,
+            Err(MicrodescError::WrongFlavor(Flavor::Ns))
+        ));
+        assert!(transport.requests.borrow().is_empty());
+    }
+
+    #[tokio::test]
+    async fn resolves_the_three_hops_in_a_single_request() {
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard").line(&m_line(1)),
+            Relay::new(2).line(&m_line(2)),
+            Relay::new(3).flags("Exit").line(&m_line(3)),
+        ]))
+        .unwrap();
+        let relays = consensus.onion_routers();
+        let path = Path {
+            guard: &relays[0],
+            middle: &relays[1],
+            exit: &relays[2],
+            flavor: Flavor::Microdesc,
+        };
+        let batch = format!(
+            "/tor/micro/d/{}",
+            [1, 2, 3]
+                .map(|id| m_line(id).trim_start_matches("m ").to_string())
+                .join("-")
+        );
+        // The microdescriptors are served in another order than requested.
+        let transport = MockTransport::new().with(
+            &batch,
+            &format!(
+                "{}{}{}",
+                microdescriptor(3),
+                microdescriptor(1),
+                microdescriptor(2)
+            ),
+        );
+
+        let resolved = path.resolve_microdescriptors(&transport).await.unwrap();
+
+        assert_eq!(vec![batch], *transport.requests.borrow());
+        for (hop, id) in [
+            (&resolved.guard, 1),
+            (&resolved.middle, 2),
+            (&resolved.exit, 3),
+        ] {
+            assert_eq!(format!("relay{}", id), hop.nickname);
+            assert_eq!(vec![id; 32], hop.microdescriptor.ntor_onion_key);
+        }
+    }
+
+    #[tokio::test]
+    async fn resolves_the_families_from_the_microdescriptors() {
+        // relay1 and relay2 list one another.
+        let family = |id: u8| {
+            microdescriptor(id).replace(
+                &format!("family ${}", fingerprint(1)),
+                &format!("family ${} ${}", fingerprint(1), fingerprint(2)),
+            )
+        };
+        let mut consensus = parse_consensus_document(&document(&[
+            Relay::new(1).line(&format!("m {}", microdesc_digest(&family(1)))),
+            Relay::new(2).line(&format!("m {}", microdesc_digest(&family(2)))),
+            Relay::new(3).line(&m_line(3)),
+        ]))
+        .unwrap();
+        let batch = format!(
+            "/tor/micro/d/{}-{}-{}",
+            microdesc_digest(&family(1)),
+            microdesc_digest(&family(2)),
+            microdesc_digest(&microdescriptor(3))
+        );
+        let transport = MockTransport::new().with(
+            &batch,
+            &format!("{}{}{}", family(2), microdescriptor(3), family(1)),
+        );
+
+        assert_eq!(3, consensus.resolve_families(&transport).await.unwrap());
+
+        let relays = consensus.onion_routers();
+        assert!(relays[0].is_in_family_with(&relays[1]));
+        assert!(!relays[0].is_in_family_with(&relays[2]));
+        assert_eq!(vec![batch], *transport.requests.borrow());
+    }
+}
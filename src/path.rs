@@ -0,0 +1,457 @@
+use crate::consensus::{Consensus, Flags, Flavor, OnionRouter, SelectionOptions};
+use rand::Rng;
+
+// https://github.com/torproject/torspec/blob/main/path-spec.txt
+// 2.2. Path selection and constraints
+//    We choose the path for each new circuit before we build it.  We choose the
+//    exit node first, followed by the other nodes in the circuit, front to back.
+//    ...
+//    - We do not choose the same router twice for the same path.
+//    - We do not choose more than one router in a given family for the same path.
+//    - We do not put any two routers from the same /16 subnet in the same path.
+
+// The number of hops of a path.
+const PATH_LENGTH: usize = 3;
+
+/// What the circuit built on a path is used for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PathPurpose {
+    /// The circuit exits to the Internet, so the last hop must allow the exit port.
+    Exit,
+    /// The circuit stays within the Tor network, e.g. to reach an onion service.
+    /// The exit port is ignored.
+    Internal,
+}
+
+/// Three onion routers to build a circuit through.
+#[derive(Debug, Clone, Copy)]
+pub struct Path<'a> {
+    pub guard: &'a OnionRouter,
+    pub middle: &'a OnionRouter,
+    /// The last hop. It is an exit relay only for `PathPurpose::Exit`.
+    pub exit: &'a OnionRouter,
+    /// The flavor of the consensus the hops were chosen from.
+    pub flavor: Flavor,
+}
+
+impl Path<'_> {
+    /// The path in the form Tor's control port takes for EXTENDCIRCUIT, e.g.
+    /// `$9695DFC35FFEB861329B9F1AB04C46397020CE31,$...,$...`.
+    //
+    // https://github.com/torproject/torspec/blob/main/control-spec.txt
+    // 3.10. EXTENDCIRCUIT
+    //    "EXTENDCIRCUIT" SP CircuitID [SP ServerSpec *("," ServerSpec)] ...
+    //    ServerSpec = LongName / Nickname
+    //    LongName = Fingerprint [ "~" Nickname ]
+    //    Fingerprint = "$" 40*HEXDIG
+    pub fn to_extendcircuit_spec(self) -> String {
+        [self.guard, self.middle, self.exit]
+            .iter()
+            .map(|or| format!("${}", or.fingerprint()))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// A position in a path.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Position {
+    Guard,
+    Middle,
+    Exit,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PathError {
+    /// No onion router is eligible for the position.
+    NoCandidate(Position),
+    /// The onion routers eligible for the position are all in the family of one already chosen.
+    SameFamily(Position),
+}
+
+/// Why no path can be built on a consensus, see `Consensus::validate_selectable`.
+#[derive(Debug, PartialEq)]
+pub enum SelectionError {
+    NoGuards,
+    NoExits,
+    /// Fewer onion routers than the hops of a path are selectable. Holds how many are.
+    NotEnoughRelays(usize),
+}
+
+impl Consensus {
+    /// Checks that there are a guard, an exit and enough other onion routers to build a path,
+    /// so that a consensus that can't be used fails before any relay is selected.
+    pub fn validate_selectable(&self) -> Result<(), SelectionError> {
+        let options = SelectionOptions::default();
+        if self.candidates(Flags::GUARD, &options).is_empty() {
+            return Err(SelectionError::NoGuards);
+        }
+        if self
+            .candidates(Flags::EXIT, &options)
+            .iter()
+            .all(|or| or.flags().contains(Flags::BAD_EXIT))
+        {
+            return Err(SelectionError::NoExits);
+        }
+        let relays = self.candidates(Flags::empty(), &options).len();
+        if relays < PATH_LENGTH {
+            return Err(SelectionError::NotEnoughRelays(relays));
+        }
+
+        Ok(())
+    }
+
+    /// The Shannon entropy, in bits, of choosing an onion router for the position with a
+    /// probability proportional to its consensus weight. The lower it is, the more the choice is
+    /// concentrated on a few onion routers. Exits are considered regardless of the port.
+    pub fn selection_entropy(&self, position: Position) -> f64 {
+        let options = SelectionOptions::default();
+        let candidates = match position {
+            Position::Guard => self.candidates(Flags::GUARD, &options),
+            Position::Middle => self.candidates(Flags::empty(), &options),
+            Position::Exit => self
+                .candidates(Flags::EXIT, &options)
+                .into_iter()
+                .filter(|or| !or.flags().contains(Flags::BAD_EXIT))
+                .collect(),
+        };
+
+        let mut weights = candidates
+            .iter()
+            .map(|or| self.consensus_weight(or, position) as f64)
+            .collect::<Vec<_>>();
+        // Chosen uniformly, as by `choose_by_weight`.
+        if weights.iter().all(|w| *w == 0.0) {
+            weights.iter_mut().for_each(|w| *w = 1.0);
+        }
+        let total = weights.iter().sum::<f64>();
+
+        weights
+            .iter()
+            .filter(|w| **w > 0.0)
+            .map(|w| {
+                let p = w / total;
+                -p * p.log2()
+            })
+            .sum()
+    }
+
+    /// Chooses a guard, a middle and an exit relay, each with a probability proportional to its
+    /// consensus weight for the position, no two of which are the same onion router, in the same
+    /// /16 subnet or in the same family. Families are only known once resolved, see
+    /// `resolve_families`. The same `rng` state yields the same path.
+    pub fn build_path<R: Rng>(
+        &self,
+        purpose: PathPurpose,
+        exit_port: u16,
+        rng: &mut R,
+    ) -> Result<Path<'_>, PathError> {
+        self.build_path_with_options(purpose, exit_port, &SelectionOptions::default(), rng)
+    }
+
+    /// Builds up to `n` paths. Each path avoids the onion routers used by the previous ones where
+    /// possible, so that the paths share as few relays as the consensus allows.
+    /// Fewer than `n` paths are returned only if no path can be built at all.
+    pub fn build_paths<R: Rng>(
+        &self,
+        n: usize,
+        purpose: PathPurpose,
+        exit_port: u16,
+        rng: &mut R,
+    ) -> Vec<Path<'_>> {
+        let mut paths = Vec::with_capacity(n);
+        let mut used = SelectionOptions::default();
+
+        for _ in 0..n {
+            let path = match self.build_path_with_options(purpose, exit_port, &used, rng) {
+                Ok(path) => path,
+                // Not enough unused onion routers left.
+                Err(_) => match self.build_path(purpose, exit_port, rng) {
+                    Ok(path) => path,
+                    Err(_) => break,
+                },
+            };
+            for or in [path.guard, path.middle, path.exit] {
+                used.exclude.insert(or.fingerprint());
+            }
+            paths.push(path);
+        }
+
+        paths
+    }
+
+    fn build_path_with_options<R: Rng>(
+        &self,
+        purpose: PathPurpose,
+        exit_port: u16,
+        options: &SelectionOptions,
+        rng: &mut R,
+    ) -> Result<Path<'_>, PathError> {
+        let exits = match purpose {
+            PathPurpose::Exit => self
+                .candidates(Flags::EXIT, options)
+                .into_iter()
+                .filter(|or| {
+                    !or.flags()
+                        .intersects(options.avoid_flags.unwrap_or(Flags::BAD_EXIT))
+                })
+                .filter(|or| or.allows_exit_port(exit_port))
+                .collect(),
+            PathPurpose::Internal => self.candidates(Flags::empty(), options),
+        };
+        let exit = self.choose_diverse(&exits, Position::Exit, &[], rng)?;
+
+        let guards = self.candidates(Flags::GUARD, options);
+        let guard = self.choose_diverse(&guards, Position::Guard, &[exit], rng)?;
+
+        let middles = self.candidates(Flags::empty(), options);
+        let middle = self.choose_diverse(&middles, Position::Middle, &[guard, exit], rng)?;
+
+        Ok(Path {
+            guard,
+            middle,
+            exit,
+            flavor: self.flavor,
+        })
+    }
+
+    // Chooses one of the candidates for the position (see `choose_by_weight`) that isn't one of
+    // the `chosen` onion routers nor in the same /16 subnet or family as any of them.
+    fn choose_diverse<'a, R: Rng>(
+        &self,
+        candidates: &[&'a OnionRouter],
+        position: Position,
+        chosen: &[&OnionRouter],
+        rng: &mut R,
+    ) -> Result<&'a OnionRouter, PathError> {
+        let candidates = candidates
+            .iter()
+            .filter(|or| {
+                chosen.iter().all(|c| {
+                    c.identity() != or.identity() && c.ip().octets()[..2] != or.ip().octets()[..2]
+                })
+            })
+            .copied()
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return Err(PathError::NoCandidate(position));
+        }
+        let candidates = candidates
+            .into_iter()
+            .filter(|or| chosen.iter().all(|c| !c.is_in_family_with(or)))
+            .collect::<Vec<_>>();
+
+        self.choose_by_weight_with_rng(&candidates, position, rng)
+            .ok_or(PathError::SameFamily(position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::parse_consensus_document;
+    use crate::testing::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::HashSet;
+
+    // Three guards, three middle relays and three exit relays allowing port 443.
+    fn consensus() -> Consensus {
+        let relays = (1..=9)
+            .map(|id| match id {
+                1..=3 => Relay::new(id).flags("Guard"),
+                4..=6 => Relay::new(id),
+                _ => Relay::new(id).flags("Exit").line("p accept 443"),
+            })
+            .collect::<Vec<_>>();
+        parse_consensus_document(&document(&relays)).unwrap()
+    }
+
+    #[test]
+    fn builds_paths_that_dont_all_share_a_guard() {
+        let consensus = consensus();
+
+        for seed in 0..20 {
+            let paths =
+                consensus.build_paths(3, PathPurpose::Exit, 443, &mut StdRng::seed_from_u64(seed));
+
+            assert_eq!(3, paths.len());
+            let mut guards = paths
+                .iter()
+                .map(|path| path.guard.nickname())
+                .collect::<Vec<_>>();
+            guards.dedup();
+            assert!(guards.len() > 1, "{:?}", guards);
+        }
+    }
+
+    #[test]
+    fn formats_the_path_for_extendcircuit() {
+        let consensus = consensus();
+        let relays = consensus.onion_routers();
+        let path = Path {
+            guard: &relays[0],
+            middle: &relays[3],
+            exit: &relays[6],
+            flavor: consensus.flavor,
+        };
+
+        assert_eq!(
+            format!(
+                "${},${},${}",
+                fingerprint(1),
+                fingerprint(4),
+                fingerprint(7)
+            ),
+            path.to_extendcircuit_spec()
+        );
+        assert_eq!(
+            "$0101010101010101010101010101010101010101",
+            path.to_extendcircuit_spec().split(',').next().unwrap()
+        );
+    }
+
+    #[test]
+    fn fails_validation_without_a_usable_exit() {
+        let relays = |exit_flags| {
+            document(&[
+                Relay::new(1).flags("Guard"),
+                Relay::new(2),
+                Relay::new(3).flags(exit_flags),
+            ])
+        };
+
+        assert_eq!(
+            Err(SelectionError::NoExits),
+            parse_consensus_document(&relays(""))
+                .unwrap()
+                .validate_selectable()
+        );
+        assert_eq!(
+            Err(SelectionError::NoExits),
+            parse_consensus_document(&relays("Exit BadExit"))
+                .unwrap()
+                .validate_selectable()
+        );
+        assert_eq!(
+            Ok(()),
+            parse_consensus_document(&relays("Exit"))
+                .unwrap()
+                .validate_selectable()
+        );
+    }
+
+    #[test]
+    fn builds_the_same_path_from_the_same_seed() {
+        let consensus = consensus();
+        let spec = |seed| {
+            consensus
+                .build_path(PathPurpose::Exit, 443, &mut StdRng::seed_from_u64(seed))
+                .unwrap()
+                .to_extendcircuit_spec()
+        };
+
+        for seed in 0..10 {
+            assert_eq!(spec(seed), spec(seed));
+        }
+        let specs = (0..10).map(spec).collect::<HashSet<_>>();
+        assert!(specs.len() > 1);
+    }
+
+    #[test]
+    fn has_lower_entropy_when_one_relay_dominates() {
+        let guards = |bandwidths: [u32; 4]| {
+            let relays = bandwidths
+                .iter()
+                .zip(1..)
+                .map(|(bandwidth, id)| Relay::new(id).flags("Guard").bandwidth(Some(*bandwidth)))
+                .collect::<Vec<_>>();
+            parse_consensus_document(&document(&relays)).unwrap()
+        };
+
+        let even = guards([1000, 1000, 1000, 1000]).selection_entropy(Position::Guard);
+        let dominated = guards([1_000_000, 1000, 1000, 1000]).selection_entropy(Position::Guard);
+
+        // Four equally likely choices.
+        assert!((even - 2.0).abs() < 1e-9, "{}", even);
+        assert!(dominated < even, "{} < {}", dominated, even);
+    }
+
+    #[test]
+    fn fails_to_build_a_path_within_one_subnet() {
+        let relays = [
+            Relay::new(1).flags("Guard"),
+            Relay::new(2),
+            Relay::new(3).flags("Exit").line("p accept 443"),
+        ];
+        let diverse = parse_consensus_document(&document(&relays)).unwrap();
+        // All three in 10.1.0.0/16.
+        let same_subnet = document(&relays)
+            .replace("10.2.0.1", "10.1.0.2")
+            .replace("10.3.0.1", "10.1.0.3");
+        let same_subnet = parse_consensus_document(&same_subnet).unwrap();
+
+        for seed in 0..10 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let path = diverse
+                .build_path(PathPurpose::Exit, 443, &mut rng)
+                .unwrap();
+            assert_eq!(
+                ["relay1", "relay2", "relay3"],
+                [path.guard, path.middle, path.exit].map(|or| or.nickname())
+            );
+            assert_eq!(
+                Err(PathError::NoCandidate(Position::Guard)),
+                same_subnet
+                    .build_path(PathPurpose::Exit, 443, &mut rng)
+                    .map(|_| ())
+            );
+        }
+    }
+
+    // Puts the onion routers with the ids in one family, each one listing all of them.
+    fn make_family(consensus: &mut Consensus, ids: &[u8]) {
+        let family = ids
+            .iter()
+            .map(|id| format!("${}", fingerprint(*id)))
+            .collect::<Vec<_>>();
+        for id in ids {
+            consensus.onion_routers[*id as usize - 1].set_family(family.clone());
+        }
+    }
+
+    #[test]
+    fn never_chooses_two_relays_of_a_family() {
+        let mut consensus = consensus();
+        make_family(&mut consensus, &[1, 4, 7]);
+
+        for seed in 0..50 {
+            let path = consensus
+                .build_path(PathPurpose::Exit, 443, &mut StdRng::seed_from_u64(seed))
+                .unwrap();
+            let in_family = [path.guard, path.middle, path.exit]
+                .iter()
+                .filter(|or| ["relay1", "relay4", "relay7"].contains(&or.nickname()))
+                .count();
+            assert!(in_family <= 1, "{:?}", path.to_extendcircuit_spec());
+        }
+    }
+
+    #[test]
+    fn fails_to_build_a_path_within_one_family() {
+        let mut consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard"),
+            Relay::new(2),
+            Relay::new(3).flags("Exit").line("p accept 443"),
+        ]))
+        .unwrap();
+        make_family(&mut consensus, &[1, 3]);
+
+        assert_eq!(
+            Err(PathError::SameFamily(Position::Guard)),
+            consensus
+                .build_path(PathPurpose::Exit, 443, &mut StdRng::seed_from_u64(0))
+                .map(|_| ())
+        );
+    }
+}
@@ -0,0 +1,377 @@
+use crate::clock::Clock;
+use crate::consensus::{Consensus, Flags, OnionRouter, SelectionOptions};
+use crate::path::Position;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+// Prefix of the cache keys holding the number of consecutive failed connection attempts
+// to an onion router.
+const CACHE_KEY_CONNECT_FAILURES_PREFIX: &str = "relay_connect_failures";
+
+fn connect_failures_key(identity: &str) -> String {
+    format!("{}/{}", CACHE_KEY_CONNECT_FAILURES_PREFIX, identity)
+}
+
+// Prefix of the cache keys holding the result of the last probe of an onion router.
+const CACHE_KEY_PROBE_PREFIX: &str = "relay_probe";
+// How long a probe result is used instead of probing again.
+const PROBE_RESULT_TTL_SECONDS: i64 = 5 * 60;
+
+fn probe_key(identity: &str) -> String {
+    format!("{}/{}", CACHE_KEY_PROBE_PREFIX, identity)
+}
+
+/// Records the result of connecting to the onion router identified by `identity`.
+/// A failure increments the failure count, a success clears it.
+pub async fn record_relay_connect_result(cache_dir: &str, identity: &str, ok: bool) {
+    let failures = if ok {
+        0
+    } else {
+        relay_connect_failures(cache_dir, identity)
+            .await
+            .saturating_add(1)
+    };
+
+    cacache::write(
+        cache_dir,
+        connect_failures_key(identity),
+        failures.to_string(),
+    )
+    .await
+    .unwrap();
+}
+
+/// The number of consecutive failed connection attempts recorded for the onion router.
+pub async fn relay_connect_failures(cache_dir: &str, identity: &str) -> u32 {
+    match cacache::read(cache_dir, connect_failures_key(identity)).await {
+        Ok(s) => String::from_utf8(s)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0),
+        Err(_) => 0,
+    }
+}
+
+/// Orders the candidates so that those with fewer recorded connection failures come first.
+/// `identity` maps a candidate to the key its results were recorded under.
+pub async fn order_by_connect_failures<T, F>(
+    cache_dir: &str,
+    candidates: Vec<T>,
+    identity: F,
+) -> Vec<T>
+where
+    F: Fn(&T) -> String,
+{
+    let mut with_failures = Vec::with_capacity(candidates.len());
+    for c in candidates {
+        let failures = relay_connect_failures(cache_dir, &identity(&c)).await;
+        with_failures.push((failures, c));
+    }
+    // Stable sort keeps the original order among candidates with the same failure count.
+    with_failures.sort_by_key(|(failures, _)| *failures);
+
+    with_failures.into_iter().map(|(_, c)| c).collect()
+}
+
+impl OnionRouter {
+    /// Measures how long it takes to establish a TCP connection to the onion router's OR port.
+    /// Fails with `ErrorKind::TimedOut` if it takes longer than `timeout`.
+    pub async fn measure_latency(&self, timeout: Duration) -> std::io::Result<Duration> {
+        let started_at = Instant::now();
+        tokio::time::timeout(timeout, TcpStream::connect(self.or_socket_addr()))
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::TimedOut, e))??;
+
+        Ok(started_at.elapsed())
+    }
+}
+
+/// Why an onion router is considered unreachable, see `Prober::probe`.
+#[derive(Debug)]
+pub enum ProbeError {
+    /// Connecting to the OR port failed or timed out.
+    Connect(std::io::Error),
+    /// A probe within the last `PROBE_RESULT_TTL_SECONDS` failed, so it wasn't probed again.
+    RecentlyUnreachable,
+}
+
+/// Probes whether onion routers accept connections, reusing the results of recent probes.
+pub struct Prober<'a, C: Clock> {
+    pub cache_dir: &'a str,
+    pub clock: &'a C,
+    /// How long to wait for a connection.
+    pub timeout: Duration,
+}
+
+impl<C: Clock> Prober<'_, C> {
+    /// Whether the onion router accepts connections, see `probe`.
+    pub async fn is_reachable(&self, or: &OnionRouter) -> bool {
+        self.probe(or).await.is_ok()
+    }
+
+    /// Checks that the onion router accepts connections. The result of a probe within the last
+    /// `PROBE_RESULT_TTL_SECONDS` is used without connecting again. The result of a new probe is
+    /// also recorded as a connection result, see `record_relay_connect_result`.
+    pub async fn probe(&self, or: &OnionRouter) -> Result<(), ProbeError> {
+        let key = probe_key(&or.fingerprint());
+        let now = self.clock.now();
+
+        match self.cached_result(&key, &now).await {
+            Some(true) => return Ok(()),
+            Some(false) => return Err(ProbeError::RecentlyUnreachable),
+            None => {}
+        }
+
+        let result = or
+            .measure_latency(self.timeout)
+            .await
+            .map(|_| ())
+            .map_err(ProbeError::Connect);
+        let reachable = result.is_ok();
+        record_relay_connect_result(self.cache_dir, &or.fingerprint(), reachable).await;
+        cacache::write(
+            self.cache_dir,
+            &key,
+            format!("{} {}", reachable, now.to_rfc3339()),
+        )
+        .await
+        .unwrap();
+
+        result
+    }
+
+    // The cached result of the probe, if it is recent enough.
+    async fn cached_result(&self, key: &str, now: &DateTime<Utc>) -> Option<bool> {
+        // "<reachable> <probed at in RFC 3339>"
+        let cached = String::from_utf8(cacache::read(self.cache_dir, key).await.ok()?).ok()?;
+        let (reachable, probed_at) = cached.split_once(' ')?;
+        let probed_at = DateTime::parse_from_rfc3339(probed_at).ok()?;
+        if probed_at.with_timezone(&Utc) + chrono::Duration::seconds(PROBE_RESULT_TTL_SECONDS)
+            <= *now
+        {
+            return None;
+        }
+
+        reachable.parse().ok()
+    }
+}
+
+impl Consensus {
+    /// Chooses a guard relay with a probability proportional to its consensus weight, and probes
+    /// whether it accepts connections. An unreachable guard is ruled out and another one is
+    /// chosen, up to `max_attempts` guards in total. Guards with fewer connection failures
+    /// recorded (see `record_relay_connect_result`) are chosen before the others. If no guard is
+    /// reachable, the error names the unreachable ones along with why.
+    pub async fn choose_reachable_guard_weighted<R: Rng, C: Clock>(
+        &self,
+        options: &SelectionOptions,
+        rng: &mut R,
+        prober: &Prober<'_, C>,
+        max_attempts: usize,
+    ) -> Result<&OnionRouter, String> {
+        let mut candidates = order_by_connect_failures(
+            prober.cache_dir,
+            self.candidates(Flags::GUARD, options),
+            |or| or.fingerprint(),
+        )
+        .await;
+        let mut unreachable = vec![];
+
+        for _ in 0..max_attempts {
+            // Guards that failed to connect less often are preferred: the guard is chosen among
+            // those with as few recorded failures as the first one.
+            let fewest = match candidates.first() {
+                Some(or) => relay_connect_failures(prober.cache_dir, &or.fingerprint()).await,
+                None => return Err("No guard relay available".to_string()),
+            };
+            let mut preferred = vec![];
+            for or in &candidates {
+                if relay_connect_failures(prober.cache_dir, &or.fingerprint()).await > fewest {
+                    break;
+                }
+                preferred.push(*or);
+            }
+            let guard = self
+                .choose_by_weight_with_rng(&preferred, Position::Guard, rng)
+                .ok_or_else(|| "No guard relay available".to_string())?;
+            match prober.probe(guard).await {
+                Ok(()) => return Ok(guard),
+                Err(e) => unreachable.push(format!("{}: {:?}", guard.nickname(), e)),
+            }
+            candidates.retain(|or| or.identity() != guard.identity());
+        }
+
+        Err(format!(
+            "No reachable guard relay found in {} attempts ({})",
+            max_attempts,
+            unreachable.join(", ")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use crate::consensus::parse_consensus_document;
+    use crate::testing::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    // Caches a probe result as if the onion router had been probed at `now`.
+    async fn cache_probe_result(cache_dir: &str, id: u8, reachable: bool, now: &DateTime<Utc>) {
+        cacache::write(
+            cache_dir,
+            probe_key(&fingerprint(id)),
+            format!("{} {}", reachable, now.to_rfc3339()),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn deprioritizes_guards_that_failed_to_connect() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache_dir = cache_dir.path().to_str().unwrap();
+        let clock = FixedClock(Utc::now());
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard").bandwidth(Some(1_000_000)),
+            Relay::new(2).flags("Guard").bandwidth(Some(1)),
+        ]))
+        .unwrap();
+        record_relay_connect_result(cache_dir, &fingerprint(1), false).await;
+        record_relay_connect_result(cache_dir, &fingerprint(1), false).await;
+        cache_probe_result(cache_dir, 1, true, &clock.0).await;
+        cache_probe_result(cache_dir, 2, true, &clock.0).await;
+        let prober = Prober {
+            cache_dir,
+            clock: &clock,
+            timeout: Duration::from_millis(100),
+        };
+
+        assert_eq!(2, relay_connect_failures(cache_dir, &fingerprint(1)).await);
+        for seed in 0..10 {
+            let guard = consensus
+                .choose_reachable_guard_weighted(
+                    &SelectionOptions::default(),
+                    &mut StdRng::seed_from_u64(seed),
+                    &prober,
+                    1,
+                )
+                .await
+                .unwrap();
+            assert_eq!("relay2", guard.nickname());
+        }
+    }
+
+    // A consensus with a single onion router listening on the local port.
+    fn local_relay(port: u16) -> Consensus {
+        let document =
+            document_with_relays(1).replace("10.1.0.1 9001", &format!("127.0.0.1 {}", port));
+        parse_consensus_document(&document).unwrap()
+    }
+
+    #[tokio::test]
+    async fn measures_the_latency_to_a_local_listener() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let consensus = local_relay(listener.local_addr().unwrap().port());
+
+        let latency = consensus.onion_routers()[0]
+            .measure_latency(Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        assert!(latency < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn fails_to_measure_the_latency_without_a_listener() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let consensus = local_relay(port);
+
+        assert!(consensus.onion_routers()[0]
+            .measure_latency(Duration::from_secs(5))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn skips_an_unreachable_guard() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache_dir = cache_dir.path().to_str().unwrap();
+        let clock = FixedClock(Utc::now());
+        let consensus = parse_consensus_document(&document(&[
+            Relay::new(1).flags("Guard").bandwidth(Some(1_000_000)),
+            Relay::new(2).flags("Guard").bandwidth(Some(1)),
+        ]))
+        .unwrap();
+        // The heaviest guard is chosen first, but it's unreachable.
+        cache_probe_result(cache_dir, 1, false, &clock.0).await;
+        cache_probe_result(cache_dir, 2, true, &clock.0).await;
+        let prober = Prober {
+            cache_dir,
+            clock: &clock,
+            timeout: Duration::from_millis(100),
+        };
+        let options = SelectionOptions::default();
+
+        assert_eq!(
+            Err(
+                "No reachable guard relay found in 1 attempts (relay1: RecentlyUnreachable)"
+                    .to_string()
+            ),
+            consensus
+                .choose_reachable_guard_weighted(
+                    &options,
+                    &mut StdRng::seed_from_u64(0),
+                    &prober,
+                    1
+                )
+                .await
+                .map(|or| or.nickname())
+        );
+        let guard = consensus
+            .choose_reachable_guard_weighted(&options, &mut StdRng::seed_from_u64(0), &prober, 2)
+            .await
+            .unwrap();
+        assert_eq!("relay2", guard.nickname());
+    }
+
+    #[tokio::test]
+    async fn uses_a_cached_probe_result_instead_of_reconnecting() {
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache_dir = cache_dir.path().to_str().unwrap();
+        // Nothing listens on the port, so connecting would fail.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let consensus = local_relay(port);
+        let or = &consensus.onion_routers()[0];
+        let probed_at = Utc::now();
+        cache_probe_result(cache_dir, 1, true, &probed_at).await;
+        let probe = |clock| async move {
+            Prober {
+                cache_dir,
+                clock: &clock,
+                timeout: Duration::from_secs(5),
+            }
+            .probe(or)
+            .await
+        };
+
+        let within_ttl = probed_at + chrono::Duration::seconds(PROBE_RESULT_TTL_SECONDS - 1);
+        assert!(probe(FixedClock(within_ttl)).await.is_ok());
+        assert_eq!(0, relay_connect_failures(cache_dir, &fingerprint(1)).await);
+
+        let past_ttl = probed_at + chrono::Duration::seconds(PROBE_RESULT_TTL_SECONDS);
+        assert!(matches!(
+            probe(FixedClock(past_ttl)).await,
+            Err(ProbeError::Connect(_))
+        ));
+        assert_eq!(1, relay_connect_failures(cache_dir, &fingerprint(1)).await);
+    }
+}
@@ -0,0 +1,366 @@
+//! Fixtures shared by the unit tests.
+
+use crate::config::{Config, OutputFormat};
+use crate::consensus::Flavor;
+use crate::microdescriptor::microdesc_digest;
+use crate::transport::{Transport, TransportError};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::thread::JoinHandle;
+
+/// The valid-after of the documents built by `document`. They are fresh for an hour and valid
+/// for three.
+pub(crate) const VALID_AFTER: &str = "2022-10-01 00:00:00";
+pub(crate) const FRESH_UNTIL: &str = "2022-10-01 01:00:00";
+pub(crate) const VALID_UNTIL: &str = "2022-10-01 03:00:00";
+
+/// Parses a time in the format of the documents, e.g. `VALID_AFTER`.
+pub(crate) fn datetime(s: &str) -> DateTime<Utc> {
+    DateTime::from_utc(
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap(),
+        Utc,
+    )
+}
+
+/// The bandwidth-weights line of the documents built by `document`.
+pub(crate) const BANDWIDTH_WEIGHTS: &str = "bandwidth-weights Wbd=2500 Wbe=0 Wbg=4203 Wbm=10000 \
+     Wdb=10000 Web=10000 Wed=2500 Wee=10000 Weg=2500 Wem=10000 Wgb=10000 Wgd=2500 Wgg=5797 \
+     Wgm=5797 Wmb=10000 Wmd=5000 Wme=0 Wmg=4203 Wmm=10000";
+
+/// The v3 identity of the authority that signs the documents built by `document` (dannenberg).
+pub(crate) const SIGNING_AUTHORITY: &str = "0232AF901C31A04EE9848595AF9BB7620D4C5B2E";
+
+/// An onion router's entry in a document. The identity consists of 20 `id` bytes, so the entries
+/// are sorted by `id`, and the address is 10.`id`.0.1, so each one is in a /16 of its own.
+#[derive(Clone)]
+pub(crate) struct Relay {
+    id: u8,
+    nickname: String,
+    flags: String,
+    published: String,
+    dir_port: u16,
+    bandwidth: Option<u32>,
+    lines: Vec<String>,
+}
+
+impl Relay {
+    /// A relay that is kept by the parser, i.e. Fast, Running, Stable and Valid, with a
+    /// bandwidth of 1000.
+    pub(crate) fn new(id: u8) -> Relay {
+        Relay {
+            id,
+            nickname: format!("relay{}", id),
+            flags: "Fast Running Stable Valid".to_string(),
+            published: "2022-09-30 12:00:00".to_string(),
+            dir_port: 0,
+            bandwidth: Some(1000),
+            lines: vec![],
+        }
+    }
+
+    pub(crate) fn nickname(mut self, nickname: &str) -> Relay {
+        self.nickname = nickname.to_string();
+        self
+    }
+
+    /// Adds flags to the "s" line, e.g. `Guard`.
+    pub(crate) fn flags(mut self, flags: &str) -> Relay {
+        self.flags = format!("{} {}", self.flags, flags);
+        self
+    }
+
+    /// Replaces the "s" line.
+    pub(crate) fn only_flags(mut self, flags: &str) -> Relay {
+        self.flags = flags.to_string();
+        self
+    }
+
+    /// Replaces the publication time, e.g. `2022-10-01 00:00:00`.
+    pub(crate) fn published(mut self, published: &str) -> Relay {
+        self.published = published.to_string();
+        self
+    }
+
+    pub(crate) fn dir_port(mut self, dir_port: u16) -> Relay {
+        self.dir_port = dir_port;
+        self
+    }
+
+    pub(crate) fn bandwidth(mut self, bandwidth: Option<u32>) -> Relay {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    /// Adds a line to the entry, e.g. `p accept 80,443`.
+    pub(crate) fn line(mut self, line: &str) -> Relay {
+        self.lines.push(line.to_string());
+        self
+    }
+
+    fn entry(&self, flavor: Flavor) -> String {
+        let digest = match flavor {
+            Flavor::Ns => format!(
+                " {}",
+                base64::encode_config([0xd0; 20], base64::STANDARD_NO_PAD)
+            ),
+            Flavor::Microdesc => String::new(),
+        };
+        let mut entry = format!(
+            "r {} {}{} {} 10.{}.0.1 9001 {}\ns {}\n",
+            self.nickname,
+            base64::encode_config(identity(self.id), base64::STANDARD_NO_PAD),
+            digest,
+            self.published,
+            self.id,
+            self.dir_port,
+            self.flags
+        );
+        if let Some(bandwidth) = self.bandwidth {
+            entry.push_str(&format!("w Bandwidth={}\n", bandwidth));
+        }
+        for line in &self.lines {
+            entry.push_str(line);
+            entry.push('\n');
+        }
+
+        entry
+    }
+}
+
+/// The identity of the `Relay` with the id.
+pub(crate) fn identity(id: u8) -> Vec<u8> {
+    vec![id; 20]
+}
+
+/// The fingerprint of the `Relay` with the id.
+pub(crate) fn fingerprint(id: u8) -> String {
+    format!("{:02X}", id).repeat(20)
+}
+
+/// The lines preceding the onion routers in the documents built by `document`.
+pub(crate) fn header(flavor: Flavor) -> String {
+    format!(
+        "network-status-version 3{}\n\
+         vote-status consensus\n\
+         consensus-method 32\n\
+         valid-after {}\n\
+         fresh-until {}\n\
+         valid-until {}\n\
+         client-versions 0.4.7.13,0.4.8.4\n\
+         server-versions 0.4.7.13,0.4.8.4\n\
+         known-flags Authority BadExit Exit Fast Guard HSDir MiddleOnly NoEdConsensus Running \
+         Stable StaleDesc Sybil V2Dir Valid\n\
+         params CircuitPriorityHalflifeMsec=30000 guard-lifetime-days=120\n\
+         dir-source dannenberg {} dannenberg.torauth.de 193.23.244.244 80 443\n",
+        match flavor {
+            Flavor::Ns => "",
+            Flavor::Microdesc => " microdesc",
+        },
+        VALID_AFTER,
+        FRESH_UNTIL,
+        VALID_UNTIL,
+        SIGNING_AUTHORITY
+    )
+}
+
+/// The lines following the onion routers in the documents built by `document`.
+pub(crate) fn footer() -> String {
+    format!(
+        "directory-footer\n\
+         {}\n\
+         directory-signature sha256 {} 1CF1DBFA3FA4EF4A1B8D8A7D8D1F9A26D3D2B5E3\n\
+         -----BEGIN SIGNATURE-----\n\
+         c2lnbmF0dXJl\n\
+         -----END SIGNATURE-----\n",
+        BANDWIDTH_WEIGHTS, SIGNING_AUTHORITY
+    )
+}
+
+/// A microdesc consensus document with the onion routers.
+pub(crate) fn document(relays: &[Relay]) -> String {
+    document_of_flavor(Flavor::Microdesc, relays)
+}
+
+pub(crate) fn document_of_flavor(flavor: Flavor, relays: &[Relay]) -> String {
+    let entries = relays
+        .iter()
+        .map(|relay| relay.entry(flavor))
+        .collect::<String>();
+    format!("{}{}{}", header(flavor), entries, footer())
+}
+
+/// A document with `n` onion routers, numbered from 1.
+pub(crate) fn document_with_relays(n: u8) -> String {
+    document(&(1..=n).map(Relay::new).collect::<Vec<_>>())
+}
+
+/// A microdescriptor with an ntor onion key and an Ed25519 identity of 32 `id` bytes each.
+pub(crate) fn microdescriptor(id: u8) -> String {
+    format!(
+        "onion-key\n\
+         -----BEGIN RSA PUBLIC KEY-----\n\
+         MIGJAoGBAMx5\n\
+         -----END RSA PUBLIC KEY-----\n\
+         ntor-onion-key {}\n\
+         family $0101010101010101010101010101010101010101\n\
+         id ed25519 {}\n",
+        base64::encode_config([id; 32], base64::STANDARD_NO_PAD),
+        base64::encode_config([id; 32], base64::STANDARD_NO_PAD)
+    )
+}
+
+/// The "m" line referring to `microdescriptor(id)`.
+pub(crate) fn m_line(id: u8) -> String {
+    format!("m {}", microdesc_digest(&microdescriptor(id)))
+}
+
+/// The path `microdescriptor(id)` is fetched from.
+pub(crate) fn microdescriptor_path(id: u8) -> String {
+    format!("/tor/micro/d/{}", microdesc_digest(&microdescriptor(id)))
+}
+
+/// The config of a run with its cache in `cache_dir`, downloading the compressed microdesc
+/// consensus without retrying.
+pub(crate) fn config(cache_dir: &Path) -> Config {
+    Config {
+        cache_dir: cache_dir.display().to_string(),
+        cache_size_cap: 1024 * 1024,
+        authority: None,
+        proxy: None,
+        timeout: None,
+        flavor: Flavor::Microdesc,
+        compressed: true,
+        skew_tolerance: chrono::Duration::zero(),
+        format: OutputFormat::Debug,
+        url: None,
+        retries_per_authority: 0,
+        offline: false,
+    }
+}
+
+/// A `Transport` that serves canned documents by path and records the paths requested.
+#[derive(Default)]
+pub(crate) struct MockTransport {
+    // The responses to the requests for a path, in order. `None` fails with a 500. The last one
+    // is repeated.
+    responses: HashMap<String, Vec<Option<String>>>,
+    // Whether `get_if_modified_since` finds the document unmodified.
+    not_modified: bool,
+    pub(crate) requests: RefCell<Vec<String>>,
+}
+
+impl MockTransport {
+    pub(crate) fn new() -> MockTransport {
+        MockTransport::default()
+    }
+
+    /// Serves `body` at `path` once the preceding responses for it have been served.
+    pub(crate) fn with(mut self, path: &str, body: &str) -> MockTransport {
+        self.responses
+            .entry(path.to_string())
+            .or_default()
+            .push(Some(body.to_string()));
+        self
+    }
+
+    /// Fails the request for `path` with a 500 once the preceding responses for it have been
+    /// served.
+    pub(crate) fn failing(mut self, path: &str) -> MockTransport {
+        self.responses
+            .entry(path.to_string())
+            .or_default()
+            .push(None);
+        self
+    }
+
+    pub(crate) fn not_modified(mut self) -> MockTransport {
+        self.not_modified = true;
+        self
+    }
+
+    fn respond(&self, path: &str) -> Result<String, TransportError> {
+        let n = self.requests.borrow().iter().filter(|p| *p == path).count();
+        self.requests.borrow_mut().push(path.to_string());
+        let responses = self
+            .responses
+            .get(path)
+            .ok_or(TransportError::Status(404))?;
+        match &responses[n.min(responses.len() - 1)] {
+            Some(body) => Ok(body.clone()),
+            None => Err(TransportError::Status(500)),
+        }
+    }
+}
+
+impl Transport for MockTransport {
+    async fn get(&self, path: &str) -> Result<String, TransportError> {
+        self.respond(path)
+    }
+
+    async fn get_if_modified_since(
+        &self,
+        path: &str,
+        _since: &DateTime<Utc>,
+    ) -> Result<Option<String>, TransportError> {
+        if self.not_modified {
+            self.requests.borrow_mut().push(path.to_string());
+            return Ok(None);
+        }
+        self.respond(path).map(Some)
+    }
+
+    async fn get_resumable(&self, path: &str, _partial: &Path) -> Result<String, TransportError> {
+        self.respond(path)
+    }
+}
+
+/// An HTTP response with a Content-Length matching `body`, unless `headers` has one.
+pub(crate) fn http_response(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+    let mut response = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+    if !headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+    {
+        response.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    for (name, value) in headers {
+        response.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    response.push_str("\r\n");
+
+    let mut response = response.into_bytes();
+    response.extend_from_slice(body);
+    response
+}
+
+/// Serves the raw HTTP `responses` on a local port, one per connection, in order. Returns the
+/// base URL, e.g. `http://127.0.0.1:12345`, and a handle that yields the heads of the requests
+/// once all the responses have been served.
+pub(crate) fn serve(responses: Vec<Vec<u8>>) -> (String, JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base_url = format!("http://{}", listener.local_addr().unwrap());
+    let handle = std::thread::spawn(move || {
+        let mut requests = vec![];
+        for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut head = vec![];
+            let mut buf = [0; 1024];
+            while !head.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                head.extend_from_slice(&buf[..n]);
+            }
+            requests.push(String::from_utf8_lossy(&head).into_owned());
+            stream.write_all(&response).unwrap();
+        }
+        requests
+    });
+
+    (base_url, handle)
+}
@@ -0,0 +1,297 @@
+use crate::decompress::{DecompressError, Decompressors};
+use chrono::{DateTime, Utc};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// Fetches documents from a directory server.
+/// Abstracted so that the way documents are fetched can be swapped out.
+// The futures aren't required to be `Send`, since they are awaited on the task that calls them.
+#[allow(async_fn_in_trait)]
+pub trait Transport {
+    /// Fetches the document at `path`, e.g. `/tor/micro/d/<digest>`.
+    async fn get(&self, path: &str) -> Result<String, TransportError>;
+
+    /// Fetches the document at `path` only if it has been modified since `since`.
+    /// Returns `None` if it hasn't.
+    async fn get_if_modified_since(
+        &self,
+        path: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Option<String>, TransportError>;
+
+    /// Fetches the document at `path`, resuming from the bytes an earlier, cut off attempt saved
+    /// to the `partial` file. The bytes are saved to `partial` as they are received, still
+    /// compressed, and the file is removed once the document is complete and decompressed.
+    async fn get_resumable(&self, path: &str, partial: &Path) -> Result<String, TransportError>;
+}
+
+#[derive(Debug)]
+pub enum TransportError {
+    Http(reqwest::Error),
+    /// The server responded with a non-success status code.
+    Status(u16),
+    /// Reading or writing the partially downloaded document failed.
+    Io(std::io::Error),
+    /// The body is shorter or longer than the Content-Length, e.g. because the connection was
+    /// dropped.
+    Truncated {
+        expected: u64,
+        received: u64,
+    },
+    /// The body couldn't be decompressed according to its Content-Encoding.
+    Decompress(DecompressError),
+}
+
+/// Fetches documents over HTTP from a directory server's dir port.
+pub struct HttpTransport {
+    client: reqwest::Client,
+    base_url: String,
+    decompressors: Decompressors,
+}
+
+impl HttpTransport {
+    /// `base_url` is the scheme and authority of the directory server, e.g. `http://127.0.0.1:80`.
+    pub fn new(base_url: String, client: reqwest::Client) -> Self {
+        HttpTransport::with_decompressors(base_url, client, Decompressors::default())
+    }
+
+    /// Same as `new`, decompressing bodies with `decompressors` instead of the default ones.
+    pub fn with_decompressors(
+        base_url: String,
+        client: reqwest::Client,
+        decompressors: Decompressors,
+    ) -> Self {
+        HttpTransport {
+            client,
+            base_url,
+            decompressors,
+        }
+    }
+
+    // Reads the body, checking that all of it was received, and decompresses it according to its
+    // Content-Encoding.
+    async fn read_body(&self, mut res: reqwest::Response) -> Result<String, TransportError> {
+        let encoding = res
+            .headers()
+            .get(reqwest::header::CONTENT_ENCODING)
+            .and_then(|encoding| encoding.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        // The length of the body as it is encoded.
+        let expected = res.content_length();
+        let mut body = vec![];
+        while let Some(chunk) = res
+            .chunk()
+            .await
+            .map_err(|e| body_error(e, expected, body.len() as u64))?
+        {
+            body.extend_from_slice(&chunk);
+        }
+        check_length(expected, body.len() as u64)?;
+        let body = self
+            .decompressors
+            .decompress(&encoding, &body)
+            .map_err(TransportError::Decompress)?;
+
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+}
+
+impl Transport for HttpTransport {
+    async fn get(&self, path: &str) -> Result<String, TransportError> {
+        let res = self
+            .client
+            .get(format!("{}{}", self.base_url, path))
+            .header(
+                reqwest::header::ACCEPT_ENCODING,
+                self.decompressors.encodings().join(", "),
+            )
+            .send()
+            .await
+            .map_err(TransportError::Http)?;
+        if !res.status().is_success() {
+            return Err(TransportError::Status(res.status().as_u16()));
+        }
+
+        self.read_body(res).await
+    }
+
+    async fn get_if_modified_since(
+        &self,
+        path: &str,
+        since: &DateTime<Utc>,
+    ) -> Result<Option<String>, TransportError> {
+        let res = self
+            .client
+            .get(format!("{}{}", self.base_url, path))
+            .header(
+                reqwest::header::ACCEPT_ENCODING,
+                self.decompressors.encodings().join(", "),
+            )
+            .header(
+                reqwest::header::IF_MODIFIED_SINCE,
+                since.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+            )
+            .send()
+            .await
+            .map_err(TransportError::Http)?;
+        if res.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        if !res.status().is_success() {
+            return Err(TransportError::Status(res.status().as_u16()));
+        }
+
+        self.read_body(res).await.map(Some)
+    }
+
+    async fn get_resumable(&self, path: &str, partial: &Path) -> Result<String, TransportError> {
+        let mut body = std::fs::read(partial).unwrap_or_default();
+        // Byte ranges refer to the body as it is encoded, so the encoded bytes are saved and only
+        // decompressed once all of them have been received.
+        let mut req = self
+            .client
+            .get(format!("{}{}", self.base_url, path))
+            .header(
+                reqwest::header::ACCEPT_ENCODING,
+                self.decompressors.encodings().join(", "),
+            );
+        if !body.is_empty() {
+            req = req.header(reqwest::header::RANGE, format!("bytes={}-", body.len()));
+        }
+        let mut res = req.send().await.map_err(TransportError::Http)?;
+
+        if res.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+            // The saved bytes don't fit the document on the server, so start over next time.
+            std::fs::remove_file(partial).map_err(TransportError::Io)?;
+            return Err(TransportError::Status(res.status().as_u16()));
+        }
+        if !res.status().is_success() {
+            return Err(TransportError::Status(res.status().as_u16()));
+        }
+        let mut file = if res.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+            OpenOptions::new().append(true).open(partial)
+        } else {
+            // The server doesn't support byte ranges, or there was nothing to resume.
+            body.clear();
+            File::create(partial)
+        }
+        .map_err(TransportError::Io)?;
+
+        let encoding = res
+            .headers()
+            .get(reqwest::header::CONTENT_ENCODING)
+            .and_then(|encoding| encoding.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let expected = res.content_length();
+        let mut received = 0;
+        while let Some(chunk) = res
+            .chunk()
+            .await
+            .map_err(|e| body_error(e, expected, received))?
+        {
+            file.write_all(&chunk).map_err(TransportError::Io)?;
+            body.extend_from_slice(&chunk);
+            received += chunk.len() as u64;
+        }
+        // The partial file is kept so that the rest can be requested next time.
+        check_length(expected, received)?;
+        std::fs::remove_file(partial).map_err(TransportError::Io)?;
+        let body = self
+            .decompressors
+            .decompress(&encoding, &body)
+            .map_err(TransportError::Decompress)?;
+
+        Ok(String::from_utf8_lossy(&body).into_owned())
+    }
+}
+
+// The error reading the body failed with. The connection being closed before Content-Length bytes
+// were received is reported as `Truncated`, like a body that ends early without an error.
+fn body_error(e: reqwest::Error, expected: Option<u64>, received: u64) -> TransportError {
+    match expected {
+        Some(expected) if e.is_body() && !e.is_timeout() && received < expected => {
+            TransportError::Truncated { expected, received }
+        }
+        _ => TransportError::Http(e),
+    }
+}
+
+fn check_length(expected: Option<u64>, received: u64) -> Result<(), TransportError> {
+    match expected {
+        Some(expected) if expected != received => {
+            Err(TransportError::Truncated { expected, received })
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+
+    #[tokio::test]
+    async fn resumes_a_compressed_download_with_a_range_request() {
+        let document = document_with_relays(3);
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(document.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let (head, tail) = compressed.split_at(compressed.len() / 2);
+        let length = compressed.len().to_string();
+        let range = format!("bytes {}-{}/{}", head.len(), compressed.len() - 1, length);
+        // The first response is cut off after the head.
+        let (base_url, requests) = serve(vec![
+            http_response(
+                "200 OK",
+                &[("Content-Encoding", "deflate"), ("Content-Length", &length)],
+                head,
+            ),
+            http_response(
+                "206 Partial Content",
+                &[("Content-Encoding", "deflate"), ("Content-Range", &range)],
+                tail,
+            ),
+        ]);
+        let partial_dir = tempfile::tempdir().unwrap();
+        let partial = partial_dir.path().join("consensus.partial");
+        let transport = HttpTransport::new(base_url, reqwest::Client::new());
+
+        assert!(matches!(
+            transport.get_resumable("/", &partial).await,
+            Err(TransportError::Truncated { expected, received })
+                if expected == compressed.len() as u64 && received == head.len() as u64
+        ));
+        assert_eq!(head, std::fs::read(&partial).unwrap());
+        let body = transport.get_resumable("/", &partial).await.unwrap();
+
+        assert_eq!(document, body);
+        assert!(!partial.exists());
+        let requests = requests.join().unwrap();
+        assert!(!requests[0].to_lowercase().contains("range:"));
+        assert!(requests[1]
+            .to_lowercase()
+            .contains(&format!("range: bytes={}-", head.len())));
+    }
+
+    #[tokio::test]
+    async fn reports_a_body_cut_off_by_the_connection_as_truncated() {
+        let document = document_with_relays(1);
+        let (base_url, _) = serve(vec![http_response(
+            "200 OK",
+            &[("Content-Length", &(document.len() + 100).to_string())],
+            document.as_bytes(),
+        )]);
+        let transport = HttpTransport::new(base_url, reqwest::Client::new());
+
+        assert!(matches!(
+            transport.get("/").await,
+            Err(TransportError::Truncated { expected, received })
+                if expected == document.len() as u64 + 100 && received == document.len() as u64
+        ));
+    }
+}